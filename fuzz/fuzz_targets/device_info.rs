@@ -0,0 +1,8 @@
+#![no_main]
+
+use ledger_installer::DeviceInfo;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = DeviceInfo::from_response(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use ledger_installer::InstalledApp;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = InstalledApp::from_response(data);
+});
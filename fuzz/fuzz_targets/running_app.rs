@@ -0,0 +1,8 @@
+#![no_main]
+
+use ledger_installer::RunningApp;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = RunningApp::from_response(data);
+});
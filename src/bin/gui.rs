@@ -0,0 +1,263 @@
+//! A minimal graphical interface to setup a Ledger without Ledger Live.
+//!
+//! Operations with the device are blocking, so they are run on a separate thread which reports
+//! back through a channel.
+
+use iced::{
+    futures::channel::mpsc,
+    widget::{button, column, container, progress_bar, row, scrollable, text},
+    Element, Length, Task,
+};
+use ledger_installer::{
+    genuine_check, install_app, list_installed_apps,
+    network::{CancelHandle, LiveNetwork},
+    transport::{wait_for_device, ReconnectingTransport, Transport},
+    uninstall_app, update_app, BulkProgress, DeviceInfo, Event, InstalledApp,
+};
+
+use std::{error, thread, time::Duration};
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Detect,
+    GenuineCheck,
+    Install { is_testnet: bool },
+    Update { is_testnet: bool },
+    Uninstall { is_testnet: bool },
+}
+
+// Messages from the thread performing an operation.
+#[derive(Debug, Clone)]
+enum WorkerMessage {
+    Event(Event),
+    Detected(DeviceInfo, Vec<InstalledApp>),
+    Finished(Result<(), String>),
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    Start(Operation),
+    Cancel,
+    Worker(WorkerMessage),
+}
+
+#[derive(Debug, Default)]
+struct Gui {
+    device: Option<(DeviceInfo, Vec<InstalledApp>)>,
+    // What the device is currently waiting for, if anything.
+    prompt: Option<String>,
+    // The outcome of the last operation.
+    status: String,
+    progress: Option<BulkProgress>,
+    busy: bool,
+    // To interrupt the session with the HSM of the ongoing operation.
+    cancel: CancelHandle,
+}
+
+// How long to wait for the device to be plugged in and unlocked.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn detect(
+    ledger_api: &impl Transport,
+    sender: &mpsc::UnboundedSender<WorkerMessage>,
+) -> Result<(), Box<dyn error::Error>> {
+    let device_info = DeviceInfo::new(ledger_api)
+        .map_err(|e| format!("Error fetching device info: {}. Is the Ledger unlocked?", e))?;
+    let _ = sender.unbounded_send(WorkerMessage::Event(Event::WaitingForConfirmation(
+        "Querying installed applications from your Ledger".to_string(),
+    )));
+    let apps = list_installed_apps(ledger_api)?;
+    let _ = sender.unbounded_send(WorkerMessage::Detected(device_info, apps));
+    Ok(())
+}
+
+// Perform this operation on the device, reporting back through this channel.
+fn perform(op: Operation, cancel: CancelHandle, sender: mpsc::UnboundedSender<WorkerMessage>) {
+    let network = LiveNetwork {
+        cancel,
+        ..LiveNetwork::default()
+    };
+    let mut on_event = |event| {
+        let _ = sender.unbounded_send(WorkerMessage::Event(event));
+    };
+    // The device is re-opened if it re-enumerates during the operation.
+    let res = wait_for_device(DEVICE_TIMEOUT, &mut on_event)
+        .and_then(|device| {
+            // The same device can't be opened twice.
+            drop(device);
+            ReconnectingTransport::new(DEVICE_TIMEOUT)
+        })
+        .and_then(|ledger_api| {
+            match op {
+                Operation::Detect => return detect(&ledger_api, &sender),
+                Operation::GenuineCheck => {
+                    genuine_check(&ledger_api, &network, &mut on_event).map(|_| ())
+                }
+                Operation::Install { is_testnet } => {
+                    install_app(&ledger_api, &network, is_testnet, &mut on_event)
+                }
+                Operation::Update { is_testnet } => {
+                    update_app(&ledger_api, &network, is_testnet, &mut on_event)
+                }
+                Operation::Uninstall { is_testnet } => {
+                    uninstall_app(&ledger_api, &network, is_testnet, &mut on_event)
+                }
+            }?;
+            // Refresh the list of installed apps.
+            detect(&ledger_api, &sender)
+        });
+    let _ = sender.unbounded_send(WorkerMessage::Finished(res.map_err(|e| e.to_string())));
+}
+
+impl Gui {
+    fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::Start(op) => {
+                self.busy = true;
+                self.progress = None;
+                self.prompt = Some("Connecting to the device...".to_string());
+                self.status.clear();
+                self.cancel = CancelHandle::new();
+                let cancel = self.cancel.clone();
+                let (sender, receiver) = mpsc::unbounded();
+                thread::spawn(move || perform(op, cancel, sender));
+                return Task::run(receiver, Message::Worker);
+            }
+            Message::Cancel => {
+                self.cancel.cancel();
+                self.prompt = Some("Cancelling...".to_string());
+            }
+            Message::Worker(WorkerMessage::Event(event)) => match event {
+                Event::WaitingForConfirmation(what) => {
+                    self.prompt = Some(format!(
+                        "{}. You might have to confirm on your device.",
+                        what
+                    ))
+                }
+                Event::ConnectingToHsm(what) => {
+                    self.prompt = Some(format!(
+                        "Querying Ledger's remote HSM to {}. You might have to confirm the operation on your device.",
+                        what
+                    ))
+                }
+                Event::DeviceDisconnected => {
+                    self.prompt = Some("Plug in your device...".to_string())
+                }
+                Event::DeviceLocked => self.prompt = Some("Unlock your device...".to_string()),
+                Event::PermissionGranted => {
+                    self.prompt = Some("Permission granted. Waiting for Ledger's remote HSM...".to_string())
+                }
+                Event::Progress(progress) => self.progress = Some(progress),
+                Event::HsmWarning(text) => self.status = format!("Warning from the HSM: {}", text),
+                Event::HsmInfo(text) => self.status = format!("Message from the HSM: {}", text),
+                Event::Done(msg) => {
+                    self.progress = None;
+                    self.status = msg;
+                }
+                // The error is reported when the operation finishes.
+                Event::Failed(_) => self.progress = None,
+            },
+            Message::Worker(WorkerMessage::Detected(device_info, apps)) => {
+                self.device = Some((device_info, apps));
+            }
+            Message::Worker(WorkerMessage::Finished(res)) => {
+                self.busy = false;
+                self.progress = None;
+                self.prompt = None;
+                if let Err(e) = res {
+                    self.status = format!("Error: {}", e);
+                }
+            }
+        }
+        Task::none()
+    }
+
+    // A button which starts this operation, disabled while another one is running.
+    fn op_button(&self, label: &str, op: Operation, enabled: bool) -> Element<'_, Message> {
+        button(text(label.to_string()))
+            .on_press_maybe((enabled && !self.busy).then_some(Message::Start(op)))
+            .into()
+    }
+
+    fn app_row(&self, name: &str, is_testnet: bool, apps: &[InstalledApp]) -> Element<'_, Message> {
+        let installed = apps.iter().any(|app| app.name.eq_ignore_ascii_case(name));
+        row![
+            text(name.to_string()).width(Length::Fixed(120.0)),
+            self.op_button("Install", Operation::Install { is_testnet }, !installed),
+            self.op_button("Update", Operation::Update { is_testnet }, installed),
+            self.op_button("Uninstall", Operation::Uninstall { is_testnet }, installed),
+        ]
+        .spacing(10)
+        .into()
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let mut content = column![
+            text("Ledger Installer").size(28),
+            self.op_button("Detect device", Operation::Detect, true),
+        ]
+        .spacing(15);
+
+        if let Some((device_info, apps)) = &self.device {
+            let model = device_info.model().unwrap_or("Unknown model");
+            content = content
+                .push(text(format!(
+                    "{} (target id {:#x})",
+                    model, device_info.target_id
+                )))
+                .push(text(format!(
+                    "Firmware version: {}. MCU version: {}.",
+                    device_info.version,
+                    device_info.mcu_version.as_deref().unwrap_or("unknown")
+                )));
+            if device_info.is_osu {
+                content = content.push(text(
+                    "A firmware upgrade is in progress, finish it before doing anything else.",
+                ));
+            }
+            content = content.push(text("Installed applications:"));
+            for app in apps {
+                let mut line = format!("  - {}", app.name);
+                if !app.is_official() {
+                    line.push_str(" (not from an official source)");
+                }
+                if app.is_debug() {
+                    line.push_str(" (debug)");
+                }
+                content = content.push(text(line));
+            }
+            content = content
+                .push(self.op_button("Genuine check", Operation::GenuineCheck, true))
+                .push(self.app_row("Bitcoin", false, apps))
+                .push(self.app_row("Bitcoin Test", true, apps));
+        }
+
+        if let Some(progress) = &self.progress {
+            content = content
+                .push(progress_bar(
+                    0.0..=progress.total.max(1) as f32,
+                    progress.sent as f32,
+                ))
+                .push(text(format!(
+                    "{}/{} commands, {}/{} bytes",
+                    progress.sent, progress.total, progress.bytes, progress.total_bytes
+                )));
+        }
+        if let Some(prompt) = &self.prompt {
+            content = content.push(text(prompt));
+        }
+        if self.busy {
+            content = content.push(button(text("Cancel")).on_press(Message::Cancel));
+        }
+        content = content.push(text(&self.status));
+
+        container(scrollable(content.padding(20)))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+}
+
+fn main() -> iced::Result {
+    iced::application("Ledger Installer", Gui::update, Gui::view).run()
+}
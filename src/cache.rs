@@ -0,0 +1,277 @@
+//! An on-disk cache of the Manager API responses, to avoid querying it over and over and to be able
+//! to work offline.
+//!
+//! Responses are keyed by their full request, which includes the target id, firmware version and
+//! provider of the device. Each is stored in its own JSON file in the cache directory.
+
+use crate::network::{HsmSocket, Network};
+use serde_derive::{Deserialize, Serialize};
+
+use std::{
+    cell::RefCell,
+    error, fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// A cached response along with the request it answers, to detect collisions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    body: Option<serde_json::Value>,
+    // When the response was fetched, in seconds since the Unix epoch.
+    fetched_at: u64,
+    response: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// FNV-1a, as it's stable across Rust versions unlike the std hasher.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Serves the Manager API responses from an on-disk cache when they are recent enough, and caches
+/// the responses from the inner network otherwise.
+///
+/// In offline mode cached responses are always used regardless of their age, and anything not
+/// cached (including connecting to the HSM) fails.
+pub struct CachingNetwork<N> {
+    inner: N,
+    dir: PathBuf,
+    ttl: Duration,
+    offline: bool,
+    // The first error when caching a response.
+    error: RefCell<Option<String>>,
+}
+
+impl<N> CachingNetwork<N> {
+    pub fn new(inner: N, dir: impl Into<PathBuf>, ttl: Duration, offline: bool) -> Self {
+        Self {
+            inner,
+            dir: dir.into(),
+            ttl,
+            offline,
+            error: RefCell::new(None),
+        }
+    }
+
+    /// The first error which occurred when caching a response, if any. Such an error doesn't fail
+    /// the request, the response just won't be served from the cache next time.
+    pub fn error(&self) -> Option<String> {
+        self.error.borrow().clone()
+    }
+
+    fn entry_path(&self, url: &str, body: Option<&serde_json::Value>) -> PathBuf {
+        let mut key = url.to_string();
+        if let Some(body) = body {
+            key.push_str(&body.to_string());
+        }
+        self.dir
+            .join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+    }
+
+    // Get the cached response to this request, if it's recent enough or we are offline.
+    fn cached(&self, url: &str, body: Option<&serde_json::Value>) -> Option<String> {
+        let data = fs::read_to_string(self.entry_path(url, body)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&data).ok()?;
+        let is_fresh = now().saturating_sub(entry.fetched_at) < self.ttl.as_secs();
+        (entry.url == url && entry.body.as_ref() == body && (is_fresh || self.offline))
+            .then_some(entry.response)
+    }
+
+    fn store(
+        &self,
+        url: &str,
+        body: Option<&serde_json::Value>,
+        response: &str,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let entry = CacheEntry {
+            url: url.to_string(),
+            body: body.cloned(),
+            fetched_at: now(),
+            response: response.to_string(),
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.entry_path(url, body), serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        url: &str,
+        body: Option<&serde_json::Value>,
+        fetch: impl FnOnce() -> Result<String, Box<dyn error::Error>>,
+    ) -> Result<String, Box<dyn error::Error>> {
+        if let Some(response) = self.cached(url, body) {
+            return Ok(response);
+        }
+        if self.offline {
+            return Err(format!("No cached response for '{}' in offline mode", url).into());
+        }
+        let response = fetch()?;
+        // Caching is best effort, failing to write an entry doesn't fail the request.
+        if let Err(e) = self.store(url, body, &response) {
+            self.error.borrow_mut().get_or_insert(format!(
+                "Could not cache the response from '{}': {}",
+                url, e
+            ));
+        }
+        Ok(response)
+    }
+}
+
+impl<N: Network> Network for CachingNetwork<N> {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        self.fetch(url, None, || self.inner.get(url))
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        self.fetch(url, Some(body), || self.inner.post_json(url, body))
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        if self.offline {
+            return Err("Can't connect to Ledger's HSM in offline mode".into());
+        }
+        self.inner.connect(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Answers every request with the number of requests made so far.
+    #[derive(Default)]
+    struct CountingNetwork {
+        requests: Cell<u32>,
+    }
+
+    impl Network for CountingNetwork {
+        fn get(&self, _url: &str) -> Result<String, Box<dyn error::Error>> {
+            self.requests.set(self.requests.get() + 1);
+            Ok(format!("response {}", self.requests.get()))
+        }
+
+        fn post_json(
+            &self,
+            url: &str,
+            _body: &serde_json::Value,
+        ) -> Result<String, Box<dyn error::Error>> {
+            self.get(url)
+        }
+
+        fn connect(&self, _url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+            Err("Connected to the HSM".into())
+        }
+    }
+
+    // A cache in its own temporary directory.
+    fn cache(name: &str, offline: bool) -> CachingNetwork<CountingNetwork> {
+        let dir = std::env::temp_dir().join(format!(
+            "ledger_installer_cache_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        CachingNetwork::new(
+            CountingNetwork::default(),
+            dir,
+            Duration::from_secs(3600),
+            offline,
+        )
+    }
+
+    const URL: &str = "https://manager.api.live.ledger.com/api/get_device_version";
+
+    #[test]
+    fn ttl() {
+        let cache = cache("ttl", false);
+        let body = serde_json::json!({"target_id": 1});
+        assert_eq!(cache.post_json(URL, &body).unwrap(), "response 1");
+        assert_eq!(cache.post_json(URL, &body).unwrap(), "response 1");
+        // Another request isn't served the cached response.
+        let other_body = serde_json::json!({"target_id": 2});
+        assert_eq!(cache.post_json(URL, &other_body).unwrap(), "response 2");
+
+        // Once expired, the response is fetched again.
+        let path = cache.entry_path(URL, Some(&body));
+        let mut entry: CacheEntry =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        entry.fetched_at -= 3600;
+        fs::write(&path, serde_json::to_string(&entry).unwrap()).unwrap();
+        assert_eq!(cache.post_json(URL, &body).unwrap(), "response 3");
+        assert_eq!(cache.post_json(URL, &body).unwrap(), "response 3");
+
+        assert_eq!(cache.error(), None);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn offline() {
+        let online = cache("offline", false);
+        online.get(URL).unwrap();
+
+        let offline = CachingNetwork::new(
+            CountingNetwork::default(),
+            &online.dir,
+            Duration::ZERO,
+            true,
+        );
+        // Cached responses are used regardless of their age.
+        assert_eq!(offline.get(URL).unwrap(), "response 1");
+        let err = offline.get(&format!("{}?other", URL)).unwrap_err();
+        assert!(err.to_string().starts_with("No cached response"), "{}", err);
+        let err = offline
+            .connect("wss://scriptrunner.api.live.ledger.com")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Can't connect to Ledger's HSM in offline mode"
+        );
+        assert_eq!(offline.inner.requests.get(), 0);
+
+        fs::remove_dir_all(&online.dir).unwrap();
+    }
+
+    #[test]
+    fn collision() {
+        let cache = cache("collision", false);
+        cache.get(URL).unwrap();
+
+        // Another request whose key would hash to the same file isn't served this response.
+        let other = format!("{}?other", URL);
+        fs::copy(cache.entry_path(URL, None), cache.entry_path(&other, None)).unwrap();
+        assert_eq!(cache.get(&other).unwrap(), "response 2");
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn write_error() {
+        // The cache directory can't be created, as it's a file.
+        let cache = cache("write_error", false);
+        fs::write(&cache.dir, "").unwrap();
+        assert_eq!(cache.get(URL).unwrap(), "response 1");
+        assert!(cache
+            .error()
+            .unwrap()
+            .starts_with("Could not cache the response from"));
+
+        fs::remove_file(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,2903 @@
+//! Setup a Ledger signing device without Ledger Live.
+//!
+//! The operations report their progress through a callback receiving [`Event`]s, so they can be
+//! embedded in a GUI or a wallet without parsing the command line output.
+
+pub mod cache;
+pub mod network;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod session;
+pub mod transport;
+
+use network::{HsmSocket, Network};
+use transport::Transport;
+
+use form_urlencoded::Serializer as UrlSerializer;
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use serde_derive::{Deserialize, Serialize};
+
+use std::{
+    cmp, error, fmt, str,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/getVersion.ts#L6
+const GET_VERSION_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xe0,
+    ins: 0x01,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L5
+const LIST_APPS_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xe0,
+    ins: 0xde,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L47
+const CONTINUE_LIST_APPS_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xe0,
+    ins: 0xdf,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/hw/openApp.ts#L3
+const OPEN_APP_COMMAND_TEMPLATE: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xe0,
+    ins: 0xd8,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// The "get app and version" command, supported by the dashboard and most apps.
+const GET_APP_AND_VERSION_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xb0,
+    ins: 0x01,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// Same as quitApp in Ledger Live.
+const QUIT_APP_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xb0,
+    ins: 0xa7,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+const LIVE_COMMON_VERSION: &str = "34.0.0";
+const PROVIDER: u32 = 1; // TODO: make it possible to set it.
+const BASE_API_V1_URL: &str = "https://manager.api.live.ledger.com/api";
+const BASE_API_V2_URL: &str = "https://manager.api.live.ledger.com/api/v2";
+const BASE_SOCKET_URL: &str = "wss://scriptrunner.api.live.ledger.com/update";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum StatusCode {
+    ACCESS_CONDITION_NOT_FULFILLED = 0x9804,
+    ALGORITHM_NOT_SUPPORTED = 0x9484,
+    CLA_NOT_SUPPORTED = 0x6e00,
+    CODE_BLOCKED = 0x9840,
+    CODE_NOT_INITIALIZED = 0x9802,
+    COMMAND_INCOMPATIBLE_FILE_STRUCTURE = 0x6981,
+    CONDITIONS_OF_USE_NOT_SATISFIED = 0x6985,
+    CONTRADICTION_INVALIDATION = 0x9810,
+    CONTRADICTION_SECRET_CODE_STATUS = 0x9808,
+    CUSTOM_IMAGE_BOOTLOADER = 0x662f,
+    CUSTOM_IMAGE_EMPTY = 0x662e,
+    FILE_ALREADY_EXISTS = 0x6a89,
+    FILE_NOT_FOUND = 0x9404,
+    GP_AUTH_FAILED = 0x6300,
+    HALTED = 0x6faa,
+    INCONSISTENT_FILE = 0x9408,
+    INCORRECT_DATA = 0x6a80,
+    INCORRECT_LENGTH = 0x6700,
+    INCORRECT_P1_P2 = 0x6b00,
+    INS_NOT_SUPPORTED = 0x6d00,
+    DEVICE_NOT_ONBOARDED = 0x6d07,
+    DEVICE_NOT_ONBOARDED_2 = 0x6611,
+    INVALID_KCV = 0x9485,
+    INVALID_OFFSET = 0x9402,
+    LICENSING = 0x6f42,
+    LOCKED_DEVICE = 0x5515,
+    MAX_VALUE_REACHED = 0x9850,
+    MEMORY_PROBLEM = 0x9240,
+    MISSING_CRITICAL_PARAMETER = 0x6800,
+    NO_EF_SELECTED = 0x9400,
+    NOT_ENOUGH_MEMORY_SPACE = 0x6a84,
+    OK = 0x9000,
+    PIN_REMAINING_ATTEMPTS = 0x63c0,
+    REFERENCED_DATA_NOT_FOUND = 0x6a88,
+    SECURITY_STATUS_NOT_SATISFIED = 0x6982,
+    TECHNICAL_PROBLEM = 0x6f00,
+    UNKNOWN_APDU = 0x6d02,
+    USER_REFUSED_ON_DEVICE = 0x5501,
+    NOT_ENOUGH_SPACE = 0x5102,
+}
+
+impl StatusCode {
+    /// Get the known status corresponding to this status word, if any.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Some(match code {
+            0x9804 => Self::ACCESS_CONDITION_NOT_FULFILLED,
+            0x9484 => Self::ALGORITHM_NOT_SUPPORTED,
+            0x6e00 => Self::CLA_NOT_SUPPORTED,
+            0x9840 => Self::CODE_BLOCKED,
+            0x9802 => Self::CODE_NOT_INITIALIZED,
+            0x6981 => Self::COMMAND_INCOMPATIBLE_FILE_STRUCTURE,
+            0x6985 => Self::CONDITIONS_OF_USE_NOT_SATISFIED,
+            0x9810 => Self::CONTRADICTION_INVALIDATION,
+            0x9808 => Self::CONTRADICTION_SECRET_CODE_STATUS,
+            0x662f => Self::CUSTOM_IMAGE_BOOTLOADER,
+            0x662e => Self::CUSTOM_IMAGE_EMPTY,
+            0x6a89 => Self::FILE_ALREADY_EXISTS,
+            0x9404 => Self::FILE_NOT_FOUND,
+            0x6300 => Self::GP_AUTH_FAILED,
+            0x6faa => Self::HALTED,
+            0x9408 => Self::INCONSISTENT_FILE,
+            0x6a80 => Self::INCORRECT_DATA,
+            0x6700 => Self::INCORRECT_LENGTH,
+            0x6b00 => Self::INCORRECT_P1_P2,
+            0x6d00 => Self::INS_NOT_SUPPORTED,
+            0x6d07 => Self::DEVICE_NOT_ONBOARDED,
+            0x6611 => Self::DEVICE_NOT_ONBOARDED_2,
+            0x9485 => Self::INVALID_KCV,
+            0x9402 => Self::INVALID_OFFSET,
+            0x6f42 => Self::LICENSING,
+            0x5515 => Self::LOCKED_DEVICE,
+            0x9850 => Self::MAX_VALUE_REACHED,
+            0x9240 => Self::MEMORY_PROBLEM,
+            0x6800 => Self::MISSING_CRITICAL_PARAMETER,
+            0x9400 => Self::NO_EF_SELECTED,
+            0x6a84 => Self::NOT_ENOUGH_MEMORY_SPACE,
+            0x9000 => Self::OK,
+            0x63c0 => Self::PIN_REMAINING_ATTEMPTS,
+            0x6a88 => Self::REFERENCED_DATA_NOT_FOUND,
+            0x6982 => Self::SECURITY_STATUS_NOT_SATISFIED,
+            0x6f00 => Self::TECHNICAL_PROBLEM,
+            0x6d02 => Self::UNKNOWN_APDU,
+            0x5501 => Self::USER_REFUSED_ON_DEVICE,
+            0x5102 => Self::NOT_ENOUGH_SPACE,
+            _ => return None,
+        })
+    }
+
+    /// Human readable description of a status word, including its name if it is known.
+    pub fn describe(code: u16) -> String {
+        match Self::from_code(code) {
+            Some(status) => format!("{:?} ({:#06x})", status, code),
+            None => format!("{:#06x}", code),
+        }
+    }
+}
+
+/// A firmware version, such as "2.1.0" or "1.0.0-rc1".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// The pre-release part, after the dash.
+    pub pre: Option<String>,
+}
+
+impl FirmwareVersion {
+    /// Parse a version as reported by the device or the Manager API. Returns `None` if it isn't
+    /// of the form "major.minor.patch", optionally followed by a dash and a pre-release part.
+    pub fn parse(version: &str) -> Option<Self> {
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, pre)) => (numbers, Some(pre.to_string())),
+            None => (version, None),
+        };
+        let mut numbers = numbers.split('.').map(|n| n.parse().ok());
+        let (major, minor, patch) = (numbers.next()??, numbers.next()??, numbers.next()??);
+        if numbers.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialOrd for FirmwareVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FirmwareVersion {
+    // A pre-release comes before the release.
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                (None, None) => cmp::Ordering::Equal,
+                (None, Some(_)) => cmp::Ordering::Greater,
+                (Some(_), None) => cmp::Ordering::Less,
+                // Fall back to the text to be consistent with equality, for instance for "rc01"
+                // and "rc1".
+                (Some(a), Some(b)) => pre_release_cmp(a, b).then_with(|| a.cmp(b)),
+            })
+    }
+}
+
+// Compare pre-releases identifier by identifier, like semver does. Numbers within an identifier
+// are compared numerically too, so "rc2" comes before "rc10".
+fn pre_release_cmp(a: &str, b: &str) -> cmp::Ordering {
+    let (mut a, mut b) = (a.split('.'), b.split('.'));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return cmp::Ordering::Equal,
+            // A larger set of identifiers comes after.
+            (None, Some(_)) => return cmp::Ordering::Less,
+            (Some(_), None) => return cmp::Ordering::Greater,
+            (Some(a), Some(b)) => match identifier_chunks(a).cmp(identifier_chunks(b)) {
+                cmp::Ordering::Equal => continue,
+                ord => return ord,
+            },
+        }
+    }
+}
+
+// A part of a pre-release identifier. Numbers come before text, as numeric identifiers come before
+// alphanumeric ones in semver.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum IdentifierChunk<'a> {
+    Number(u64),
+    Text(&'a str),
+}
+
+// Split an identifier into its runs of digits and of other characters.
+fn identifier_chunks(identifier: &str) -> impl Iterator<Item = IdentifierChunk<'_>> {
+    let mut rest = identifier;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let len = rest
+            .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(len);
+        rest = tail;
+        Some(match chunk.parse() {
+            Ok(n) if first.is_ascii_digit() => IdentifierChunk::Number(n),
+            _ => IdentifierChunk::Text(chunk),
+        })
+    })
+}
+
+/// The state of the device, decoded from the flags of its response to the GET_VERSION command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceFlags {
+    /// Whether the device was set up, with a PIN and a seed.
+    pub is_onboarded: bool,
+    pub pin_validated: bool,
+    pub manager_allowed: bool,
+    pub is_recovery_mode: bool,
+    /// The language of the device's interface, if reported.
+    pub language_id: Option<u8>,
+}
+
+impl DeviceFlags {
+    const RECOVERY_MODE: u8 = 0x01;
+    const ONBOARDED: u8 = 0x04;
+    const MANAGER_ALLOWED: u8 = 0x08;
+    const PIN_VALIDATED: u8 = 0x80;
+
+    /// Decode the flags as reported by the device.
+    ///
+    /// Adapted from Ledger Live's getVersion use case. Older devices only report the first byte, in
+    /// which case they are assumed to be onboarded and not in recovery mode.
+    pub fn from_bytes(flags: &[u8]) -> Self {
+        let flag = flags.first().copied().unwrap_or(0);
+        let extended = flags.len() == 4;
+        Self {
+            is_onboarded: !extended || flag & Self::ONBOARDED != 0,
+            pin_validated: flag & Self::PIN_VALIDATED != 0,
+            manager_allowed: flag & Self::MANAGER_ALLOWED != 0,
+            is_recovery_mode: extended && flag & Self::RECOVERY_MODE != 0,
+            language_id: if extended { Some(flags[3]) } else { None },
+        }
+    }
+}
+
+// NOTE: MCU target id is always == target_id in Ledger Live
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub target_id: u32,
+    /// The firmware version, without the "-osu" suffix when running the OS updater.
+    pub version: String,
+    pub flags: Vec<u8>,
+    pub is_bootloader: bool,
+    /// Whether the device is running the OS updater, that is it's in the middle of a firmware
+    /// upgrade. The version is then the one of the firmware being installed.
+    pub is_osu: bool,
+    pub se_version: Option<String>,
+    pub se_target_id: u32,
+    pub mcu_version: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Name of the device model, if known. Deduced from the target id.
+    pub fn model(&self) -> Option<&'static str> {
+        Some(match self.target_id >> 16 {
+            0x3100 | 0x3101 => "Ledger Blue",
+            0x3110 => "Ledger Nano S",
+            0x3300 => "Ledger Nano X",
+            0x3310 => "Ledger Nano S Plus",
+            0x3320 => "Ledger Stax",
+            0x3330 => "Ledger Flex",
+            _ => return None,
+        })
+    }
+
+    /// Storage available on the device and size of a storage block, in bytes. Deduced from the
+    /// model.
+    pub fn storage(&self) -> Option<(u64, u64)> {
+        Some(match self.target_id >> 16 {
+            0x3100 | 0x3101 => (480 * 1024, 4 * 1024),
+            0x3110 => (320 * 1024, 4 * 1024),
+            0x3300 => (2 * 1024 * 1024, 4 * 1024),
+            0x3310 | 0x3320 | 0x3330 => (1533 * 1024, 32),
+            _ => return None,
+        })
+    }
+
+    /// The firmware version, if it could be parsed.
+    pub fn firmware_version(&self) -> Option<FirmwareVersion> {
+        FirmwareVersion::parse(&self.version)
+    }
+
+    /// The state of the device, decoded from its flags.
+    pub fn device_flags(&self) -> DeviceFlags {
+        DeviceFlags::from_bytes(&self.flags)
+    }
+
+    /// Query information about this device.
+    pub fn new(ledger_api: &impl Transport) -> Result<Self, Box<dyn error::Error>> {
+        let ver_answer = ledger_api.exchange(&GET_VERSION_COMMAND)?;
+        Self::from_answer(&ver_answer)
+    }
+
+    // Parse the device's answer to the GET_VERSION command, which is refused if it's locked.
+    fn from_answer(answer: &APDUAnswer<Vec<u8>>) -> Result<Self, Box<dyn error::Error>> {
+        if answer.retcode() == StatusCode::LOCKED_DEVICE as u16 {
+            return Err("The device is locked".into());
+        }
+        Self::from_response(answer.data())
+    }
+
+    /// Parse the data of the device's response to the GET_VERSION command.
+    ///
+    /// Adapted from https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/parseGetVersionResponse.ts
+    pub fn from_response(data: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let mut i = 0;
+
+        if data.len() < 5 {
+            return Err("Not enough data".into());
+        }
+        let target_id = u32::from_be_bytes(data[i..i + 4].try_into()?);
+        i += 4;
+        let raw_ver_len = data[i] as usize;
+        i += 1;
+
+        if data.len() < i + raw_ver_len + 1 {
+            return Err("Not enough data".into());
+        }
+        let raw_ver = &data[i..i + raw_ver_len];
+        i += raw_ver_len;
+        let version = str::from_utf8(raw_ver)?;
+        let is_osu = version.contains("-osu");
+        let version = version.replace("-osu", "");
+        let flags_len = data[i] as usize;
+        i += 1;
+
+        if data.len() < i + flags_len {
+            return Err("Not enough data".into());
+        }
+        let flags = &data[i..i + flags_len];
+        i += flags_len;
+
+        let is_bootloader = (target_id & 4026531840) != 805306368;
+        Ok(if is_bootloader {
+            if data.len() < i + 1 {
+                return Err("Not enough data".into());
+            }
+            let part1_len = data[i] as usize;
+            i += 1;
+
+            if data.len() < i + part1_len {
+                return Err("Not enough data".into());
+            }
+            let part1 = &data[i..i + part1_len];
+            i += part1_len;
+
+            if part1_len >= 5 {
+                let se_version = str::from_utf8(part1)?;
+
+                if data.len() < i + 1 {
+                    return Err("Not enough data".into());
+                }
+                let part2_len = data[i] as usize;
+                i += 1;
+
+                if data.len() < i + part2_len {
+                    return Err("Not enough data".into());
+                }
+                let part2 = &data[i..i + part2_len];
+                //i += part2_len;
+                let se_target_id = u32::from_be_bytes(
+                    part2
+                        .try_into()
+                        .map_err(|_| "Invalid SE target id length")?,
+                );
+
+                Self {
+                    target_id,
+                    version: version.to_string(),
+                    flags: flags.to_vec(),
+                    is_bootloader,
+                    is_osu,
+                    se_version: Some(se_version.to_string()),
+                    se_target_id,
+                    mcu_version: None,
+                }
+            } else {
+                let se_target_id = u32::from_be_bytes(
+                    part1
+                        .try_into()
+                        .map_err(|_| "Invalid SE target id length")?,
+                );
+
+                Self {
+                    target_id,
+                    version: version.to_string(),
+                    flags: flags.to_vec(),
+                    is_bootloader,
+                    is_osu,
+                    se_version: None,
+                    se_target_id,
+                    mcu_version: None,
+                }
+            }
+        } else {
+            if data.len() < i + 1 {
+                return Err("Not enough data".into());
+            }
+            let mcu_len = data[i] as usize;
+            i += 1;
+
+            if data.len() < i + mcu_len {
+                return Err("Not enough data".into());
+            }
+            let mcu = &data[i..i + mcu_len];
+            //i += mcu_len;
+            let mcu = match mcu.split_last() {
+                Some((0, mcu)) => mcu,
+                _ => mcu,
+            };
+            let mcu_version = str::from_utf8(mcu)?;
+
+            Self {
+                target_id,
+                version: version.to_string(),
+                flags: flags.to_vec(),
+                is_bootloader,
+                is_osu,
+                se_version: Some(version.to_string()),
+                se_target_id: target_id,
+                mcu_version: Some(mcu_version.to_string()),
+            }
+        })
+    }
+}
+
+/// The app currently running on the device.
+#[derive(Debug, Clone)]
+pub struct RunningApp {
+    pub name: String,
+    pub version: String,
+    pub flags: Vec<u8>,
+}
+
+impl RunningApp {
+    // The name reported when no app is running.
+    const DASHBOARD_NAME: &'static str = "BOLOS";
+
+    /// Query the app currently running on the device.
+    pub fn new(ledger_api: &impl Transport) -> Result<Self, Box<dyn error::Error>> {
+        let answer = ledger_api.exchange(&GET_APP_AND_VERSION_COMMAND)?;
+        Self::from_answer(&answer)
+    }
+
+    // Parse the device's answer to the "get app and version" command.
+    fn from_answer(answer: &APDUAnswer<Vec<u8>>) -> Result<Self, Box<dyn error::Error>> {
+        if answer.retcode() != StatusCode::OK as u16 {
+            return Err(format!(
+                "Error getting the running app. Status: {}",
+                StatusCode::describe(answer.retcode())
+            )
+            .into());
+        }
+        Self::from_response(answer.data())
+    }
+
+    /// Parse the data of the device's response to the "get app and version" command.
+    pub fn from_response(data: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        if data.first() != Some(&0x01) {
+            return Err("Unexpected format of the app and version response.".into());
+        }
+        let mut i = 1;
+
+        // Each field is prefixed by its length.
+        let mut fields = Vec::with_capacity(3);
+        for _ in 0..3 {
+            let len = *data.get(i).ok_or("Not enough data")? as usize;
+            i += 1;
+            fields.push(data.get(i..i + len).ok_or("Not enough data")?);
+            i += len;
+        }
+
+        Ok(Self {
+            name: str::from_utf8(fields[0])?.to_string(),
+            version: str::from_utf8(fields[1])?.to_string(),
+            flags: fields[2].to_vec(),
+        })
+    }
+
+    /// Whether no app is running, the device being on its dashboard.
+    pub fn is_dashboard(&self) -> bool {
+        self.name == Self::DASHBOARD_NAME
+    }
+}
+
+/// The flags of an installed app, describing its capabilities and how it was installed.
+///
+/// Adapted from the `APPLICATION_FLAG_*` definitions of the BOLOS SDK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppFlags(pub u16);
+
+impl AppFlags {
+    /// The app was signed by the issuer, that is Ledger.
+    pub const ISSUER: u16 = 0x0001;
+    pub const BOLOS_UPGRADE: u16 = 0x0002;
+    /// The app was signed, with any key.
+    pub const SIGNED: u16 = 0x0004;
+    pub const BOLOS_UX: u16 = 0x0008;
+    /// The app can derive the master key.
+    pub const DERIVE_MASTER: u16 = 0x0010;
+    pub const SHARED_NVRAM: u16 = 0x0020;
+    /// The app can access the global PIN.
+    pub const GLOBAL_PIN: u16 = 0x0040;
+    /// The app was installed in debug mode.
+    pub const DEBUG: u16 = 0x0080;
+    pub const AUTOBOOT: u16 = 0x0100;
+    pub const BOLOS_SETTINGS: u16 = 0x0200;
+    /// The app was signed by a custom certificate authority.
+    pub const CUSTOM_CA: u16 = 0x0400;
+    pub const LIBRARY: u16 = 0x0800;
+    pub const NO_RUN: u16 = 0x1000;
+    pub const ENABLED: u16 = 0x8000;
+
+    const NAMES: [(u16, &'static str); 14] = [
+        (Self::ISSUER, "issuer"),
+        (Self::BOLOS_UPGRADE, "bolos upgrade"),
+        (Self::SIGNED, "signed"),
+        (Self::BOLOS_UX, "bolos ux"),
+        (Self::DERIVE_MASTER, "derive master"),
+        (Self::SHARED_NVRAM, "shared nvram"),
+        (Self::GLOBAL_PIN, "global pin"),
+        (Self::DEBUG, "debug"),
+        (Self::AUTOBOOT, "autoboot"),
+        (Self::BOLOS_SETTINGS, "bolos settings"),
+        (Self::CUSTOM_CA, "custom ca"),
+        (Self::LIBRARY, "library"),
+        (Self::NO_RUN, "no run"),
+        (Self::ENABLED, "enabled"),
+    ];
+
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// Names of the flags which are set. Unknown bits are ignored.
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InstalledApp {
+    pub name: String,
+    pub hash: Vec<u8>,
+    pub hash_code_data: Vec<u8>,
+    pub blocks: u16,
+    pub flags: u16,
+}
+
+impl InstalledApp {
+    pub fn app_flags(&self) -> AppFlags {
+        AppFlags(self.flags)
+    }
+
+    /// Whether the app was installed from an official source, that is signed by Ledger and not by
+    /// a custom certificate authority.
+    pub fn is_official(&self) -> bool {
+        let flags = self.app_flags();
+        flags.contains(AppFlags::ISSUER) && !flags.contains(AppFlags::CUSTOM_CA)
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.app_flags().contains(AppFlags::DEBUG)
+    }
+
+    /// Parse the data of one of the device's responses to the LIST_APPS or CONTINUE_LIST_APPS
+    /// commands. The list may be spread across several responses.
+    ///
+    /// See https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L9
+    pub fn from_response(data: &[u8]) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let mut installed_apps = Vec::new();
+        let mut i = 0;
+        if data.first() != Some(&0x01) {
+            return Err("Unexpected listApps response format.".into());
+        }
+        i += 1;
+
+        while i < data.len() {
+            if data.len() < i + 1 + 2 + 2 + 32 + 32 + 1 {
+                return Err("Not enough data".into());
+            }
+
+            let len = data[i] as usize;
+            i += 1;
+            let blocks = u16::from_be_bytes(data[i..i + 2].try_into()?);
+            i += 2;
+            let flags = u16::from_be_bytes(data[i..i + 2].try_into()?);
+            i += 2;
+            let hash_code_data = data[i..i + 32].to_vec();
+            i += 32;
+            let hash = data[i..i + 32].to_vec();
+            i += 32;
+            let name_len = data[i] as usize;
+            i += 1;
+
+            if data.len() < i + name_len {
+                return Err("Not enough data".into());
+            }
+            if len != name_len + 70 {
+                return Err("Invalid listApps length data.".into());
+            }
+            let name = str::from_utf8(&data[i..i + name_len])?.to_string();
+            i += name_len;
+
+            installed_apps.push(Self {
+                name,
+                hash,
+                hash_code_data,
+                blocks,
+                flags,
+            });
+        }
+
+        Ok(installed_apps)
+    }
+}
+
+/// An estimate of the use of the device's storage, from the size of the installed apps.
+///
+/// The firmware uses some of the storage too, so the free space is an upper bound.
+#[derive(Debug, Clone)]
+pub struct StorageReport {
+    /// Storage available on the device, in bytes.
+    pub total: u64,
+    /// Size of a storage block, in bytes. Apps use a whole number of blocks.
+    pub block_size: u64,
+    /// Name of each installed app along with the storage it uses, in bytes.
+    pub apps: Vec<(String, u64)>,
+    /// Storage used by all the installed apps, in bytes.
+    pub used: u64,
+    /// Storage left, in bytes.
+    pub free: u64,
+}
+
+impl StorageReport {
+    /// Returns `None` if the storage of this device model is unknown.
+    pub fn new(device_info: &DeviceInfo, apps: &[InstalledApp]) -> Option<Self> {
+        let (total, block_size) = device_info.storage()?;
+        let apps: Vec<_> = apps
+            .iter()
+            .map(|app| (app.name.clone(), app.blocks as u64 * block_size))
+            .collect();
+        let used = apps.iter().map(|(_, size)| size).sum();
+        Some(Self {
+            total,
+            block_size,
+            apps,
+            used,
+            free: total.saturating_sub(used),
+        })
+    }
+
+    /// Storage an app of this size would use once installed, in bytes.
+    pub fn required_space(&self, app_size: u64) -> u64 {
+        app_size.div_ceil(self.block_size) * self.block_size
+    }
+
+    /// The apps to uninstall to free up this much storage, biggest first. Empty if it can't be
+    /// done.
+    pub fn suggest_removals(&self, needed: u64) -> Vec<&str> {
+        let mut apps: Vec<_> = self.apps.iter().collect();
+        apps.sort_by_key(|(_, size)| cmp::Reverse(*size));
+        let mut freed = 0;
+        let mut removals = Vec::new();
+        for (name, size) in apps {
+            if freed >= needed {
+                break;
+            }
+            freed += size;
+            removals.push(name.as_str());
+        }
+        if freed >= needed {
+            removals
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum HsmMessageData {
+    Command(String),
+    CommandList(Vec<String>),
+    // Anything else, for instance the result of an operation.
+    Other(serde_json::Value),
+}
+
+impl HsmMessageData {
+    fn into_value(self) -> serde_json::Value {
+        match self {
+            Self::Command(c) => c.into(),
+            Self::CommandList(l) => l.into(),
+            Self::Other(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct HsmMessage {
+    pub query: String,
+    // Only set for queries expecting a response.
+    #[serde(default)]
+    pub nonce: Option<u32>,
+    pub data: Option<HsmMessageData>,
+    // Sometimes used instead of the data for the final result.
+    #[serde(default)]
+    pub result: Option<serde_json::Value>,
+}
+
+fn deser_apdu_command(hex_str: &str) -> Result<APDUCommand<Vec<u8>>, Box<dyn error::Error>> {
+    let bytes = hex::decode(hex_str)?;
+    if bytes.len() < 5 {
+        return Err("Invalid command".into());
+    }
+
+    let (cla, ins, p1, p2, data_len) = (bytes[0], bytes[1], bytes[2], bytes[3], bytes[4] as usize);
+    if bytes.len() != 5 + data_len {
+        return Err("Invalid command".into());
+    }
+
+    Ok(APDUCommand {
+        cla,
+        ins,
+        p1,
+        p2,
+        data: bytes[5..].to_vec(),
+    })
+}
+
+/// Progress of the transfer of a bulk of APDUs from the HSM to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct BulkProgress {
+    /// Number of commands sent to the device so far.
+    pub sent: usize,
+    /// Total number of commands in this bulk.
+    pub total: usize,
+    /// Number of bytes sent to the device so far.
+    pub bytes: usize,
+    /// Total number of bytes in this bulk.
+    pub total_bytes: usize,
+}
+
+/// An update on the progress of an operation, for instance to be displayed to the user.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The device is being queried. The user might have to confirm on the device.
+    WaitingForConfirmation(String),
+    /// Connecting to Ledger's remote HSM for this purpose. The user might have to confirm the
+    /// operation on the device.
+    ConnectingToHsm(String),
+    /// No device is plugged in. Emitted when waiting for one.
+    DeviceDisconnected,
+    /// The device is locked. Emitted when waiting for it to be unlocked.
+    DeviceLocked,
+    /// The permission requested by the HSM was granted on the device.
+    PermissionGranted,
+    /// Progress of a bulk transfer from the HSM to the device.
+    Progress(BulkProgress),
+    /// The HSM sent a warning. Contains the full message.
+    HsmWarning(String),
+    /// The HSM sent an informational message, or one we don't know about but can safely ignore.
+    /// Contains the full message.
+    HsmInfo(String),
+    /// The operation succeeded.
+    Done(String),
+    /// The operation failed.
+    Failed(String),
+}
+
+// Emit the final event for an operation depending on its outcome.
+fn report<T, E: fmt::Display>(
+    res: Result<T, E>,
+    success_msg: &str,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<T, E> {
+    match res {
+        Ok(r) => {
+            on_event(Event::Done(success_msg.to_string()));
+            Ok(r)
+        }
+        Err(e) => {
+            on_event(Event::Failed(e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+// A query from the HSM on the websocket, along with its nonce to be used in our response.
+#[derive(Debug, Clone)]
+enum HsmQuery {
+    // Send this command to the device and respond with its answer.
+    Exchange(u32, APDUCommand<Vec<u8>>),
+    // Send all these commands to the device, then respond once. The commands are given along with
+    // their index in the list sent by the HSM, which may contain empty entries, followed by the
+    // length of this list.
+    Bulk(u32, Vec<(usize, APDUCommand<Vec<u8>>)>, usize),
+    // The operation succeeded, along with its result if any.
+    Success(Option<serde_json::Value>),
+    Error,
+    Warning,
+    // The HSM is about to ask for a permission on the device, such as allowing the Manager.
+    PermissionRequested,
+    // The permission was granted on the device.
+    PermissionGranted,
+    // The HSM's own account of the progress of a bulk transfer. We track it ourselves through the
+    // answers of the device, so it's only passed along as information.
+    BulkProgress,
+    Info,
+    // A query we don't know about but which doesn't expect a response, so can be ignored.
+    Unknown,
+}
+
+// Parse a text message received from the HSM on the websocket.
+fn parse_hsm_query(text: &str) -> Result<HsmQuery, Box<dyn error::Error>> {
+    let msg: HsmMessage = serde_json::from_str(text)?;
+
+    // The dance is usually:
+    // - first the HSM sends a few standalone commands;
+    // - then it sends a bunch in bulk;
+    // - finally it sends a success.
+    Ok(match msg.query.as_str() {
+        "exchange" => {
+            let command_hex = match msg.data {
+                Some(HsmMessageData::Command(h)) => h,
+                _ => return Err("A single command is expected in 'exchange' mode.".into()),
+            };
+            let nonce = msg.nonce.ok_or("Missing nonce in 'exchange' query.")?;
+            HsmQuery::Exchange(nonce, deser_apdu_command(&command_hex)?)
+        }
+        "bulk" => {
+            let commands = match msg.data {
+                Some(HsmMessageData::CommandList(l)) => l,
+                _ => return Err("Expecting a list of commands in bulk mode.".into()),
+            };
+            let len = commands.len();
+            let commands = commands
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| !c.is_empty())
+                .map(|(i, c)| Ok((i, deser_apdu_command(c)?)))
+                .collect::<Result<_, Box<dyn error::Error>>>()?;
+            let nonce = msg.nonce.ok_or("Missing nonce in 'bulk' query.")?;
+            HsmQuery::Bulk(nonce, commands, len)
+        }
+        "success" => HsmQuery::Success(msg.data.map(HsmMessageData::into_value).or(msg.result)),
+        "error" => HsmQuery::Error,
+        "warning" => HsmQuery::Warning,
+        "device-permission-requested" => HsmQuery::PermissionRequested,
+        "device-permission-granted" => HsmQuery::PermissionGranted,
+        "bulk-progress" => HsmQuery::BulkProgress,
+        "info" => HsmQuery::Info,
+        // The HSM waits for a response we don't know how to give, better to stop here than to
+        // hang.
+        _ if msg.nonce.is_some() => {
+            return Err(format!(
+                "Got an unsupported query on the ws. Full message: {}.",
+                text
+            )
+            .into())
+        }
+        _ => HsmQuery::Unknown,
+    })
+}
+
+// The event to emit for an informational query from the HSM, if any.
+fn hsm_query_event(query: &HsmQuery, text: &str) -> Option<Event> {
+    match query {
+        HsmQuery::Warning => Some(Event::HsmWarning(text.to_string())),
+        HsmQuery::PermissionRequested => Some(Event::WaitingForConfirmation(
+            "Ledger's remote HSM is requesting a permission on your device".to_string(),
+        )),
+        HsmQuery::PermissionGranted => Some(Event::PermissionGranted),
+        HsmQuery::BulkProgress | HsmQuery::Info | HsmQuery::Unknown => {
+            Some(Event::HsmInfo(text.to_string()))
+        }
+        _ => None,
+    }
+}
+
+// Get the query out of a message received from the HSM on the websocket, if there is one.
+fn ws_message_text(msg: tungstenite::Message) -> Result<Option<String>, String> {
+    match msg {
+        // It appears they only exchange JSON text messages, but accept them as binary too.
+        tungstenite::Message::Text(text) => Ok(Some(text)),
+        tungstenite::Message::Binary(data) => String::from_utf8(data)
+            .map(Some)
+            .map_err(|_| "Got a binary message on the ws which isn't valid text.".to_string()),
+        // Pings are answered by the session itself.
+        tungstenite::Message::Ping(_)
+        | tungstenite::Message::Pong(_)
+        | tungstenite::Message::Frame(_) => Ok(None),
+        // We close the connection ourselves after a success and stop reading the queries once we
+        // got it, so the HSM closing it always means the operation was interrupted.
+        tungstenite::Message::Close(frame) => Err(match frame {
+            Some(frame) if !frame.reason.is_empty() => format!(
+                "The HSM closed the connection: {} (code {}).",
+                frame.reason,
+                u16::from(frame.code)
+            ),
+            Some(frame) => format!(
+                "The HSM closed the connection before the operation completed (code {}).",
+                u16::from(frame.code)
+            ),
+            None => "The HSM closed the connection before the operation completed.".to_string(),
+        }),
+    }
+}
+
+// The response to an 'exchange' query, given the device's answer.
+fn exchange_response(nonce: u32, resp: &APDUAnswer<Vec<u8>>) -> String {
+    // NOTE: the HSM expects only the data, not the last two bytes of the raw
+    // response (the status) in the "data" field below.
+    let response = if resp.retcode() == StatusCode::OK as u16 {
+        "success"
+    } else {
+        "error"
+    };
+    let resp_data = hex::encode(resp.data());
+
+    serde_json::json!({
+        "nonce": nonce,
+        "response": response,
+        "data": resp_data,
+    })
+    .to_string()
+}
+
+// The error for an 'error' query from the HSM. The HSM usually gives up after the device refused a
+// command, in which case its status is the most useful part.
+fn hsm_error(text: &str, failed_status: Option<u16>) -> String {
+    let mut msg = format!("Got an 'error' query on the ws. Full message: {}.", text);
+    if let Some(status) = failed_status {
+        msg.push_str(&format!(
+            " The device answered a command with status {}.",
+            StatusCode::describe(status)
+        ));
+    }
+    msg
+}
+
+// The response to a 'bulk' query. On failure, the status of the failed command (or the transport
+// error) is sent.
+fn bulk_response(nonce: u32, failure: Option<BulkFailure>) -> String {
+    match failure {
+        Some(failure) => serde_json::json!({
+            "nonce": nonce,
+            "response": "error",
+            "data": match failure {
+                BulkFailure::Status(retcode) => hex::encode(retcode.to_be_bytes()),
+                BulkFailure::Transport(e) => e,
+            },
+        }),
+        None => serde_json::json!({
+            "nonce": nonce,
+            "response": "success",
+            "data": "",
+        }),
+    }
+    .to_string()
+}
+
+// The progress of a bulk transfer before any command was sent.
+fn bulk_progress(commands: &[(usize, APDUCommand<Vec<u8>>)]) -> BulkProgress {
+    BulkProgress {
+        sent: 0,
+        total: commands.len(),
+        bytes: 0,
+        total_bytes: commands.iter().map(|(_, c)| apdu_size(c)).sum(),
+    }
+}
+
+fn apdu_size(command: &APDUCommand<Vec<u8>>) -> usize {
+    5 + command.data.len()
+}
+
+// Why a command in a bulk failed.
+#[derive(Debug, Clone)]
+enum BulkFailure {
+    // The device answered with this status.
+    Status(u16),
+    // The command could not be exchanged with the device.
+    Transport(String),
+}
+
+// The error for the command at this index in the list sent by the HSM.
+fn bulk_failure(index: usize, len: usize, failure: &BulkFailure) -> String {
+    let cause = match failure {
+        BulkFailure::Status(retcode) => format!("status {}", StatusCode::describe(*retcode)),
+        BulkFailure::Transport(e) => format!("error: {}", e),
+    };
+    format!(
+        "Command {} out of {} in bulk failed with {}",
+        index + 1,
+        len,
+        cause
+    )
+}
+
+// What has to be done next in a session with the HSM.
+#[derive(Debug)]
+enum HsmStep {
+    // Read the next message from the HSM and pass it to the session.
+    Read,
+    // Send this command to the device and pass its answer to the session.
+    Exchange(APDUCommand<Vec<u8>>),
+    // Send this message to the HSM.
+    Send(tungstenite::Message),
+    // Send this message to the HSM to let it know the operation failed, then fail with this error.
+    Fail(tungstenite::Message, String),
+    // The operation succeeded, along with its result if any.
+    Done(Option<serde_json::Value>),
+}
+
+// A bulk of commands being sent to the device.
+#[derive(Debug)]
+struct BulkTransfer {
+    nonce: u32,
+    commands: std::vec::IntoIter<(usize, APDUCommand<Vec<u8>>)>,
+    // Length of the list sent by the HSM.
+    len: usize,
+    // Index and size of the command sent last.
+    current: (usize, usize),
+    progress: BulkProgress,
+}
+
+// The query the device's answers are for.
+#[derive(Debug)]
+enum PendingQuery {
+    Exchange(u32),
+    Bulk(BulkTransfer),
+}
+
+// A session with the HSM, independent of how we talk to the device and to the HSM so it can be
+// driven both by the blocking and by the async flows. The driver performs each step and passes the
+// outcome back to the session.
+// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/socket/index.ts#L95
+#[derive(Debug, Default)]
+struct HsmSession {
+    // The status of the last command sent in 'exchange' mode, if the device refused it.
+    failed_status: Option<u16>,
+    pending: Option<PendingQuery>,
+}
+
+impl HsmSession {
+    // Handle a message received from the HSM.
+    fn on_message(
+        &mut self,
+        msg: tungstenite::Message,
+        on_event: &mut dyn FnMut(Event),
+    ) -> Result<HsmStep, String> {
+        // Answer pings ourselves rather than leaving it to the websocket library, so the answer is
+        // part of the session and of its recordings.
+        if let tungstenite::Message::Ping(payload) = msg {
+            return Ok(HsmStep::Send(tungstenite::Message::Pong(payload)));
+        }
+        let text = match ws_message_text(msg)? {
+            Some(text) => text,
+            None => return Ok(HsmStep::Read),
+        };
+
+        Ok(match parse_hsm_query(&text).map_err(|e| e.to_string())? {
+            HsmQuery::Exchange(nonce, command) => {
+                self.pending = Some(PendingQuery::Exchange(nonce));
+                HsmStep::Exchange(command)
+            }
+            HsmQuery::Bulk(nonce, commands, len) => {
+                // Ledger Live closes the socket immediately after receiving a bulk. It doesn't
+                // appear to be necessary, on the contrary if we don't we get a clean "success"
+                // response back. So we might as well do that.
+                let progress = bulk_progress(&commands);
+                on_event(Event::Progress(progress));
+                self.bulk_step(BulkTransfer {
+                    nonce,
+                    commands: commands.into_iter(),
+                    len,
+                    current: (0, 0),
+                    progress,
+                })
+            }
+            HsmQuery::Success(result) => HsmStep::Done(result),
+            HsmQuery::Error => return Err(hsm_error(&text, self.failed_status)),
+            query => {
+                if let Some(event) = hsm_query_event(&query, &text) {
+                    on_event(event);
+                }
+                HsmStep::Read
+            }
+        })
+    }
+
+    // Handle the device's answer to the command of the last 'Exchange' step.
+    fn on_answer(
+        &mut self,
+        answer: Result<APDUAnswer<Vec<u8>>, String>,
+        on_event: &mut dyn FnMut(Event),
+    ) -> Result<HsmStep, String> {
+        match self.pending.take() {
+            Some(PendingQuery::Exchange(nonce)) => {
+                let resp = answer?;
+                self.failed_status =
+                    (resp.retcode() != StatusCode::OK as u16).then_some(resp.retcode());
+                Ok(HsmStep::Send(tungstenite::Message::Text(
+                    exchange_response(nonce, &resp),
+                )))
+            }
+            Some(PendingQuery::Bulk(mut bulk)) => {
+                let failure = match answer {
+                    Ok(resp) if resp.retcode() == StatusCode::OK as u16 => None,
+                    Ok(resp) => Some(BulkFailure::Status(resp.retcode())),
+                    Err(e) => Some(BulkFailure::Transport(e)),
+                };
+
+                // Stop at the first failure and let the server know the operation failed.
+                if let Some(failure) = failure {
+                    let msg = bulk_failure(bulk.current.0, bulk.len, &failure);
+                    let ws_resp = bulk_response(bulk.nonce, Some(failure));
+                    return Ok(HsmStep::Fail(tungstenite::Message::Text(ws_resp), msg));
+                }
+
+                bulk.progress.sent += 1;
+                bulk.progress.bytes += bulk.current.1;
+                on_event(Event::Progress(bulk.progress));
+                Ok(self.bulk_step(bulk))
+            }
+            None => Err("Got an answer from the device without having sent a command".to_string()),
+        }
+    }
+
+    // Send the next command of this bulk, or respond to the HSM once they were all sent.
+    fn bulk_step(&mut self, mut bulk: BulkTransfer) -> HsmStep {
+        match bulk.commands.next() {
+            Some((index, command)) => {
+                bulk.current = (index, apdu_size(&command));
+                self.pending = Some(PendingQuery::Bulk(bulk));
+                HsmStep::Exchange(command)
+            }
+            None => HsmStep::Send(tungstenite::Message::Text(bulk_response(bulk.nonce, None))),
+        }
+    }
+}
+
+// Some actions, such as installing apps or upgrading the firmware, are done in Ledger Live by
+// opening a socket so a remote server communicates directly with the Ledger. It appears to be
+// talking to an HSM up there which would manage sensitive actions.
+// Parameters are passed directly in the url. Don't forget to escape the necessary characters!
+// A progress event is emitted before and after every command sent in a bulk.
+// Returns the result sent by the HSM along with the final success, if any.
+pub fn query_via_websocket(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    url: &str,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<Option<serde_json::Value>, Box<dyn error::Error>> {
+    let _session = HsmSessionGuard::new(ledger_api);
+    hsm_session(ledger_api, network, url, on_event)
+}
+
+// Signals the transport that a session with the HSM is ongoing for as long as it's alive, so its end
+// is signaled however it ends.
+struct HsmSessionGuard<'a, T: Transport>(&'a T);
+
+impl<'a, T: Transport> HsmSessionGuard<'a, T> {
+    fn new(ledger_api: &'a T) -> Self {
+        ledger_api.set_hsm_session(true);
+        Self(ledger_api)
+    }
+}
+
+impl<T: Transport> Drop for HsmSessionGuard<'_, T> {
+    fn drop(&mut self) {
+        self.0.set_hsm_session(false);
+    }
+}
+
+fn hsm_session(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    url: &str,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<Option<serde_json::Value>, Box<dyn error::Error>> {
+    let mut socket = network.connect(url)?;
+    let mut session = HsmSession::default();
+    let mut step = HsmStep::Read;
+
+    loop {
+        step = match step {
+            HsmStep::Read => session.on_message(socket.read()?, on_event)?,
+            HsmStep::Exchange(command) => {
+                socket.check_interrupted()?;
+                let answer = ledger_api.exchange(&command).map_err(|e| e.to_string());
+                session.on_answer(answer, on_event)?
+            }
+            HsmStep::Send(msg) => {
+                socket.send(msg)?;
+                HsmStep::Read
+            }
+            HsmStep::Fail(msg, e) => {
+                socket.send(msg)?;
+                return Err(e.into());
+            }
+            HsmStep::Done(result) => {
+                close_socket(socket.as_mut());
+                return Ok(result);
+            }
+        }
+    }
+}
+
+// Close the connection once the operation succeeded, waiting for the HSM to acknowledge it. Errors
+// don't matter anymore at this point.
+fn close_socket(socket: &mut dyn HsmSocket) {
+    if socket.close().is_ok() {
+        while let Ok(msg) = socket.read() {
+            if msg.is_close() {
+                break;
+            }
+        }
+    }
+}
+
+/// List the applications installed on the device. The user might have to confirm on the device.
+pub fn list_installed_apps(
+    ledger_api: &impl Transport,
+) -> Result<Vec<InstalledApp>, Box<dyn error::Error>> {
+    let mut answer = ledger_api.exchange(&LIST_APPS_COMMAND)?;
+    let mut installed_apps = Vec::new();
+    while !answer.data().is_empty() {
+        installed_apps.extend(InstalledApp::from_response(answer.data())?);
+        answer = ledger_api.exchange(&CONTINUE_LIST_APPS_COMMAND)?;
+    }
+
+    Ok(installed_apps)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceVersion {
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirmwareInfo {
+    pub id: i64,
+    pub perso: String,
+}
+
+impl FirmwareInfo {
+    pub fn from_device(
+        network: &dyn Network,
+        device_info: &DeviceInfo,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        Ok(query_firmware_info(network, device_info)?.1)
+    }
+}
+
+// Query the device version of this device, then the info about its firmware.
+fn query_firmware_info(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+) -> Result<(DeviceVersion, FirmwareInfo), Box<dyn error::Error>> {
+    let (dev_ver_url, dev_ver_body) = device_version_request(device_info);
+    let dev_ver_resp = network.post_json(&dev_ver_url, &dev_ver_body)?;
+    let device_version: DeviceVersion = serde_json::from_str(&dev_ver_resp)?;
+
+    let (firm_url, firm_body) = firmware_version_request(device_info, &device_version);
+    let firm_resp = network.post_json(&firm_url, &firm_body)?;
+    Ok((device_version, serde_json::from_str(&firm_resp)?))
+}
+
+// Url and body of the request for the device version of this device.
+fn device_version_request(device_info: &DeviceInfo) -> (String, serde_json::Value) {
+    let url = UrlSerializer::new(format!("{}/get_device_version?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .finish();
+    let body = serde_json::json!({
+    "provider": PROVIDER,
+    "target_id": device_info.target_id,
+    });
+    (url, body)
+}
+
+// Url and body of the request for the firmware info of this device.
+fn firmware_version_request(
+    device_info: &DeviceInfo,
+    device_version: &DeviceVersion,
+) -> (String, serde_json::Value) {
+    let url = UrlSerializer::new(format!("{}/get_firmware_version?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .finish();
+    let body = serde_json::json!({
+    "provider": PROVIDER,
+    "device_version": device_version.id,
+    "version_name": &device_info.version,
+    });
+    (url, body)
+}
+
+// Url of the websocket to perform the genuine check for this device.
+fn genuine_check_url(device_info: &DeviceInfo, firmware_info: &FirmwareInfo) -> String {
+    UrlSerializer::new(format!("{}/genuine?", BASE_SOCKET_URL))
+        .append_pair("targetId", &device_info.target_id.to_string())
+        .append_pair("perso", &firmware_info.perso)
+        .finish()
+}
+
+// Add some context to an error, unless it's an interrupted session which callers may want to tell
+// apart.
+fn with_context(e: Box<dyn error::Error>, context: &str) -> Box<dyn error::Error> {
+    if e.is::<network::SessionError>() {
+        e
+    } else {
+        format!("{}: {}", context, e).into()
+    }
+}
+
+// Check the device is in a state where the operations of this crate can be performed.
+fn check_device_ready(device_info: &DeviceInfo) -> Result<(), String> {
+    if device_info.is_osu {
+        return Err(format!(
+            "The device is in the middle of an upgrade to firmware version {}. Finish the upgrade first.",
+            device_info.version
+        ));
+    }
+    // The bootloader reports different flags.
+    if !device_info.is_bootloader {
+        let flags = device_info.device_flags();
+        if flags.is_recovery_mode {
+            return Err("The device is in recovery mode. Restart it normally first.".to_string());
+        }
+        if !flags.is_onboarded {
+            return Err(
+                "The device isn't set up yet. Set it up with a PIN and a seed first.".to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Get device info, with a hint if the device doesn't respond as expected. Errors if the device isn't
+// ready to be used.
+fn device_info(ledger_api: &impl Transport) -> Result<DeviceInfo, Box<dyn error::Error>> {
+    let device_info = DeviceInfo::new(ledger_api)
+        .map_err(|e| format!("Error fetching device info: {}. Is the Ledger unlocked?", e))?;
+    check_device_ready(&device_info)?;
+    Ok(device_info)
+}
+
+/// The details of a successful genuine check, which can be exported (for instance as JSON) as a
+/// proof the check was performed.
+#[derive(Debug, Clone, Serialize)]
+pub struct GenuineCheckReport {
+    pub target_id: u32,
+    pub firmware_version: String,
+    pub mcu_version: Option<String>,
+    /// The personalization of the device the check was performed for.
+    pub perso: String,
+    /// The final result sent by Ledger's HSM, if any.
+    pub result: Option<serde_json::Value>,
+    /// When the check was completed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl GenuineCheckReport {
+    fn new(
+        device_info: &DeviceInfo,
+        firmware_info: &FirmwareInfo,
+        result: Option<serde_json::Value>,
+    ) -> Self {
+        Self {
+            target_id: device_info.target_id,
+            firmware_version: device_info.version.clone(),
+            mcu_version: device_info.mcu_version.clone(),
+            perso: firmware_info.perso.clone(),
+            result,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Check the device is genuine through Ledger's remote HSM.
+pub fn genuine_check(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<GenuineCheckReport, Box<dyn error::Error>> {
+    let res = genuine_check_inner(ledger_api, network, on_event);
+    report(res, "Success. Your Ledger is genuine.", on_event)
+}
+
+fn genuine_check_inner(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<GenuineCheckReport, Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    let firmware_info = FirmwareInfo::from_device(network, &device_info)
+        .map_err(|e| format!("Error querying firmware information for this device: {}", e))?;
+
+    on_event(Event::ConnectingToHsm(
+        "perform the genuine check".to_string(),
+    ));
+    let genuine_ws_url = genuine_check_url(&device_info, &firmware_info);
+    let result = query_via_websocket(ledger_api, network, &genuine_ws_url, on_event)
+        .map_err(|e| with_context(e, "Error when performing genuine check"))?;
+    Ok(GenuineCheckReport::new(
+        &device_info,
+        &firmware_info,
+        result,
+    ))
+}
+
+// The OS updater to install to upgrade to the next firmware. The queries for the latest firmware
+// are the same as getLatestFirmware in Ledger Live's Manager API.
+#[derive(Debug, Clone, Deserialize)]
+struct OsuFirmware {
+    next_se_firmware_final_version: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LatestFirmwareResponse {
+    // "null" if the device is running the latest firmware already.
+    result: String,
+    #[serde(default)]
+    se_firmware_osu_version: Option<OsuFirmware>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FinalFirmware {
+    name: String,
+    #[serde(default)]
+    notes: Option<String>,
+    // The ids of the MCU versions this firmware can run with.
+    #[serde(default)]
+    mcu_versions: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McuVersion {
+    id: i64,
+    name: String,
+    // The bootloader version the MCU can be installed from, or "none".
+    from_bootloader_version: String,
+}
+
+// Url and body of the request for the firmware to upgrade this device to.
+fn latest_firmware_request(
+    device_version: &DeviceVersion,
+    firmware_info: &FirmwareInfo,
+) -> (String, serde_json::Value) {
+    let url = UrlSerializer::new(format!("{}/get_latest_firmware?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .finish();
+    let body = serde_json::json!({
+    "provider": PROVIDER,
+    "current_se_firmware_final_version": firmware_info.id,
+    "device_version": device_version.id,
+    });
+    (url, body)
+}
+
+fn final_firmware_url(id: i64) -> String {
+    UrlSerializer::new(format!(
+        "{}/firmware_final_versions/{}?",
+        BASE_API_V1_URL, id
+    ))
+    .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+    .finish()
+}
+
+fn mcu_versions_url() -> String {
+    UrlSerializer::new(format!("{}/mcu_versions?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .finish()
+}
+
+// Url and body of the request for the MCU version to install from this bootloader version.
+fn next_mcu_request(device_info: &DeviceInfo) -> (String, serde_json::Value) {
+    let url = UrlSerializer::new(format!("{}/mcu_versions_bootloader?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .finish();
+    let body = serde_json::json!({
+    "bootloader_version": &device_info.version,
+    });
+    (url, body)
+}
+
+// The MCU version to install on a device in bootloader mode, as Ledger Live's getNextMCU. There is
+// none if the MCU is already up to date, in which case the Manager API answers "default".
+fn query_next_mcu(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+) -> Result<Option<McuVersion>, Box<dyn error::Error>> {
+    let (url, body) = next_mcu_request(device_info);
+    let resp = network
+        .post_json(&url, &body)
+        .map_err(|e| format!("Error when querying the next MCU version: {}", e))?;
+    Ok(parse_next_mcu(&resp)?)
+}
+
+fn parse_next_mcu(resp: &str) -> Result<Option<McuVersion>, String> {
+    let next: serde_json::Value = serde_json::from_str(resp)
+        .map_err(|e| format!("Error when deserializing the next MCU version: {}", e))?;
+    if next.is_null() || next == "default" {
+        return Ok(None);
+    }
+    serde_json::from_value(next)
+        .map(Some)
+        .map_err(|e| format!("Error when deserializing the next MCU version: {}", e))
+}
+
+fn query_mcu_versions(network: &dyn Network) -> Result<Vec<McuVersion>, Box<dyn error::Error>> {
+    let resp = network
+        .get(&mcu_versions_url())
+        .map_err(|e| format!("Error when querying the MCU versions: {}", e))?;
+    Ok(serde_json::from_str(&resp)
+        .map_err(|e| format!("Error when deserializing the MCU versions: {}", e))?)
+}
+
+/// A newer firmware available for a device.
+#[derive(Debug, Clone)]
+pub struct LatestFirmware {
+    pub version: String,
+    pub release_notes: Option<String>,
+    /// The MCU versions this firmware can run with.
+    pub mcu_versions: Vec<String>,
+    /// Whether the MCU of the device has to be updated as part of the upgrade.
+    pub needs_mcu_update: bool,
+}
+
+/// The firmware of a device, compared to the latest one published by Ledger.
+#[derive(Debug, Clone)]
+pub struct FirmwareStatus {
+    pub target_id: u32,
+    pub se_version: String,
+    pub mcu_version: Option<String>,
+    /// The firmware to upgrade to, if the device isn't running the latest one already.
+    pub latest: Option<LatestFirmware>,
+}
+
+/// Check whether a newer firmware is available for the device.
+pub fn firmware_status(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+) -> Result<FirmwareStatus, Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    if device_info.is_bootloader {
+        return Err(
+            "The device is in bootloader mode. Restart it normally to check its firmware.".into(),
+        );
+    }
+    let (device_version, firmware_info) = query_firmware_info(network, &device_info)
+        .map_err(|e| format!("Error querying firmware information for this device: {}", e))?;
+    let (url, body) = latest_firmware_request(&device_version, &firmware_info);
+    let resp = network
+        .post_json(&url, &body)
+        .map_err(|e| format!("Error when querying the latest firmware: {}", e))?;
+    let latest: LatestFirmwareResponse = serde_json::from_str(&resp)
+        .map_err(|e| format!("Error when deserializing the latest firmware: {}", e))?;
+
+    let latest = match latest.se_firmware_osu_version {
+        Some(osu) if latest.result != "null" => {
+            let resp = network
+                .get(&final_firmware_url(osu.next_se_firmware_final_version))
+                .map_err(|e| format!("Error when querying the latest firmware: {}", e))?;
+            let final_firmware: FinalFirmware = serde_json::from_str(&resp)
+                .map_err(|e| format!("Error when deserializing the latest firmware: {}", e))?;
+            let mcu_versions: Vec<String> = query_mcu_versions(network)?
+                .into_iter()
+                .filter(|mcu| final_firmware.mcu_versions.contains(&mcu.id))
+                .map(|mcu| mcu.name)
+                .collect();
+            let needs_mcu_update = device_info
+                .mcu_version
+                .as_ref()
+                .is_some_and(|v| !mcu_versions.contains(v));
+            Some(LatestFirmware {
+                version: final_firmware.name,
+                release_notes: final_firmware.notes,
+                mcu_versions,
+                needs_mcu_update,
+            })
+        }
+        _ => None,
+    };
+    Ok(FirmwareStatus {
+        target_id: device_info.target_id,
+        se_version: device_info.version,
+        mcu_version: device_info.mcu_version,
+        latest,
+    })
+}
+
+// The numbers of a version such as "2.30" or "1.16.0", to compare MCU and bootloader versions which
+// don't always have a patch number.
+fn version_numbers(version: &str) -> Vec<u32> {
+    version.split('.').map_while(|n| n.parse().ok()).collect()
+}
+
+// Url of the websocket to flash this version of the MCU, or of the bootloader.
+fn mcu_update_url(device_info: &DeviceInfo, version: &str) -> String {
+    UrlSerializer::new(format!("{}/mcu?", BASE_SOCKET_URL))
+        .append_pair("targetId", &device_info.target_id.to_string())
+        .append_pair("version", version)
+        .finish()
+}
+
+/// What was done by an MCU update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum McuUpdate {
+    /// The device already runs the latest MCU version.
+    UpToDate,
+    /// The bootloader had to be updated to this version first. The MCU update has to be performed
+    /// again once the device restarted in bootloader mode.
+    Bootloader(String),
+    /// The MCU was updated to this version.
+    Mcu(String),
+}
+
+impl fmt::Display for McuUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UpToDate => write!(f, "The MCU is already up to date."),
+            Self::Bootloader(version) => write!(
+                f,
+                "Updated the bootloader to version {}. Run the MCU update again once the device restarted in bootloader mode.",
+                version
+            ),
+            Self::Mcu(version) => write!(f, "Successfully updated the MCU to version {}.", version),
+        }
+    }
+}
+
+/// Update the MCU of a device in bootloader mode to the next version published for its bootloader.
+/// Like Ledger Live, the bootloader is updated first if the MCU can't be installed from the current
+/// one.
+pub fn update_mcu(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<McuUpdate, Box<dyn error::Error>> {
+    let res = update_mcu_inner(ledger_api, network, on_event);
+    let msg = res.as_ref().map(|u| u.to_string()).unwrap_or_default();
+    report(res, &msg, on_event)
+}
+
+// What to flash to install this MCU version: the MCU itself, or first the bootloader it has to be
+// installed from. Returns the version to flash along with the resulting update.
+fn plan_mcu_update(device_info: &DeviceInfo, mcu: McuVersion) -> (String, McuUpdate) {
+    // In bootloader mode the device reports the version of its bootloader. It's compared on as many
+    // numbers as the required one has, so that "1.16" and "1.16.0" are the same version.
+    let wanted = version_numbers(&mcu.from_bootloader_version);
+    let mut current = version_numbers(&device_info.version);
+    current.resize(wanted.len(), 0);
+    let needs_bootloader = mcu.from_bootloader_version != "none" && current != wanted;
+    if needs_bootloader {
+        let version = mcu.from_bootloader_version;
+        (version.clone(), McuUpdate::Bootloader(version))
+    } else {
+        (mcu.name.clone(), McuUpdate::Mcu(mcu.name))
+    }
+}
+
+// Let the user know what is about to be flashed through the HSM.
+fn mcu_update_event(update: &McuUpdate, version: &str) -> Event {
+    Event::ConnectingToHsm(format!(
+        "update the {} to version {}",
+        if matches!(update, McuUpdate::Bootloader(_)) {
+            "bootloader"
+        } else {
+            "MCU"
+        },
+        version
+    ))
+}
+
+// Refuse to update the MCU of a device which isn't in bootloader mode.
+fn check_bootloader_mode(device_info: &DeviceInfo) -> Result<(), String> {
+    if !device_info.is_bootloader {
+        return Err("The device must be in bootloader mode to update its MCU".to_string());
+    }
+    Ok(())
+}
+
+fn update_mcu_inner(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<McuUpdate, Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    check_bootloader_mode(&device_info)?;
+    let mcu = match query_next_mcu(network, &device_info)? {
+        Some(mcu) => mcu,
+        None => return Ok(McuUpdate::UpToDate),
+    };
+    let (version, update) = plan_mcu_update(&device_info, mcu);
+    on_event(mcu_update_event(&update, &version));
+    query_via_websocket(
+        ledger_api,
+        network,
+        &mcu_update_url(&device_info, &version),
+        on_event,
+    )
+    .map_err(|e| with_context(e, "Error when updating the MCU"))?;
+    Ok(update)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BitcoinAppV2 {
+    #[serde(rename = "versionName")]
+    version_name: String,
+    perso: String,
+    delete: String,
+    #[serde(rename = "deleteKey")]
+    delete_key: String,
+    firmware: String,
+    #[serde(rename = "firmwareKey")]
+    firmware_key: String,
+    hash: String,
+    // Size of the app, in bytes.
+    #[serde(default)]
+    bytes: Option<u64>,
+    #[serde(default)]
+    version: Option<String>,
+    // The kind of app, for instance "currency", "plugin" or "tool".
+    #[serde(default, rename = "type")]
+    app_type: Option<String>,
+}
+
+// Name of the Bitcoin app, lowercased.
+fn lowercase_app_name(is_testnet: bool) -> &'static str {
+    if is_testnet {
+        "bitcoin test"
+    } else {
+        "bitcoin"
+    }
+}
+
+// List the applications installed on the device, letting the user know they might have to confirm.
+fn query_installed_apps(
+    ledger_api: &impl Transport,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<Vec<InstalledApp>, Box<dyn error::Error>> {
+    on_event(Event::WaitingForConfirmation(
+        "Querying installed applications from your Ledger".to_string(),
+    ));
+    Ok(list_installed_apps(ledger_api)
+        .map_err(|e| format!("Error listing installed applications: {}", e))?)
+}
+
+// Whether the Bitcoin app is among these installed apps.
+fn has_bitcoin_app(apps: &[InstalledApp], is_testnet: bool) -> bool {
+    apps.iter()
+        .any(|app| app.name.to_lowercase() == lowercase_app_name(is_testnet))
+}
+
+// Whether the Bitcoin app is currently installed on the device.
+fn is_app_installed(
+    ledger_api: &impl Transport,
+    is_testnet: bool,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<bool, Box<dyn error::Error>> {
+    let apps = query_installed_apps(ledger_api, on_event)?;
+    Ok(has_bitcoin_app(&apps, is_testnet))
+}
+
+// Make sure there is enough storage left to install this app, as the HSM would otherwise only fail
+// with NOT_ENOUGH_SPACE halfway through. Passes if the storage can't be estimated.
+fn check_free_space(
+    device_info: &DeviceInfo,
+    apps: &[InstalledApp],
+    bitcoin_app: &BitcoinAppV2,
+) -> Result<(), String> {
+    let (storage, app_size) = match (StorageReport::new(device_info, apps), bitcoin_app.bytes) {
+        (Some(storage), Some(app_size)) => (storage, app_size),
+        _ => return Ok(()),
+    };
+    let required = storage.required_space(app_size);
+    if required <= storage.free {
+        return Ok(());
+    }
+
+    let mut msg = format!(
+        "Not enough storage left on the device to install the app: it needs {} KB but at most {} KB are free.",
+        required / 1024,
+        storage.free / 1024
+    );
+    let removals = storage.suggest_removals(required - storage.free);
+    if !removals.is_empty() {
+        msg.push_str(&format!(" Consider uninstalling {}.", removals.join(", ")));
+    }
+    Err(msg)
+}
+
+// Get the Bitcoin app information for this device.
+// This uses the v2 API. See for reference:
+// - https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/apps/listApps/v2.ts
+// - https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/device-core/src/managerApi/repositories/HttpManagerApiRepository.ts#L211
+// This only gives the latest version of the app. The v1 API, which lists the previous versions too,
+// is used to install a specific version. See `query_app_versions`.
+fn bitcoin_app_info(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+    is_testnet: bool,
+) -> Result<BitcoinAppV2, Box<dyn error::Error>> {
+    let url = apps_by_target_url(device_info.target_id, &device_info.version);
+    let resp_apps = network.get(&url).map_err(|e| {
+        format!(
+            "Error when querying information about the Bitcoin app for this device: {}",
+            e
+        )
+    })?;
+    find_bitcoin_app(&resp_apps, is_testnet)
+}
+
+fn apps_by_target_url(target_id: u32, firmware_version: &str) -> String {
+    UrlSerializer::new(format!("{}/apps/by-target?", BASE_API_V2_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .append_pair("provider", &PROVIDER.to_string()) // TODO: allow to configure the provider
+        .append_pair("target_id", &target_id.to_string())
+        .append_pair("firmware_version_name", firmware_version)
+        .finish()
+}
+
+// Parse the response to the apps by target query.
+fn parse_app_catalogue(resp_apps: &str) -> Result<Vec<BitcoinAppV2>, Box<dyn error::Error>> {
+    Ok(serde_json::from_str(resp_apps).map_err(|e| {
+        format!(
+            "Error when deserializing response into list of apps info: {}",
+            e
+        )
+    })?)
+}
+
+// Find the Bitcoin app in the response to the apps by target query.
+fn find_bitcoin_app(
+    resp_apps: &str,
+    is_testnet: bool,
+) -> Result<BitcoinAppV2, Box<dyn error::Error>> {
+    let bitcoin_app = parse_app_catalogue(resp_apps)?
+        .into_iter()
+        // FIXME: is versionName guaranteed to be the name? What's "version" for?
+        .find(|o| o.version_name.to_lowercase() == lowercase_app_name(is_testnet))
+        .ok_or("Could not get info about Bitcoin app")?;
+    Ok(bitcoin_app)
+}
+
+// An app as returned by the v1 API. It lists all the versions of the apps published for a firmware,
+// not only the latest ones.
+// See https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/manager/index.ts#L103-L104.
+#[derive(Debug, Clone, Deserialize)]
+struct AppVersionV1 {
+    name: String,
+    version: String,
+    perso: String,
+    delete: String,
+    delete_key: String,
+    firmware: String,
+    firmware_key: String,
+    hash: String,
+    #[serde(default)]
+    bytes: Option<u64>,
+}
+
+impl From<AppVersionV1> for BitcoinAppV2 {
+    fn from(app: AppVersionV1) -> Self {
+        Self {
+            version_name: app.name,
+            perso: app.perso,
+            delete: app.delete,
+            delete_key: app.delete_key,
+            firmware: app.firmware,
+            firmware_key: app.firmware_key,
+            hash: app.hash,
+            bytes: app.bytes,
+            version: Some(app.version),
+            app_type: None,
+        }
+    }
+}
+
+// The versions are kept undecoded so that an incomplete one, for instance with a null field, only
+// leaves that one out.
+#[derive(Debug, Clone, Deserialize)]
+struct AppsV1 {
+    application_versions: Vec<serde_json::Value>,
+}
+
+// Url and body of the request for all the versions of the apps available for this firmware.
+fn apps_v1_request(
+    device_info: &DeviceInfo,
+    device_version: &DeviceVersion,
+    firmware_info: &FirmwareInfo,
+) -> (String, serde_json::Value) {
+    let url = UrlSerializer::new(format!("{}/get_apps?", BASE_API_V1_URL))
+        .append_pair("livecommonversion", LIVE_COMMON_VERSION)
+        .append_pair("firmware_version_name", &device_info.version)
+        .finish();
+    let body = serde_json::json!({
+    "provider": PROVIDER,
+    "current_se_firmware_final_version": firmware_info.id,
+    "device_version": device_version.id,
+    });
+    (url, body)
+}
+
+// Get all the published versions of all the apps for this device, latest first.
+fn query_all_app_versions(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+) -> Result<Vec<BitcoinAppV2>, Box<dyn error::Error>> {
+    let (device_version, firmware_info) = query_firmware_info(network, device_info)
+        .map_err(|e| format!("Error querying firmware information for this device: {}", e))?;
+    let (url, body) = apps_v1_request(device_info, &device_version, &firmware_info);
+    let resp = network
+        .post_json(&url, &body)
+        .map_err(|e| format!("Error when querying the versions of the apps: {}", e))?;
+    Ok(parse_app_versions(&resp)?)
+}
+
+// Parse the response to the v1 apps query into all the versions of the apps, latest first. Versions
+// which lack some of the information needed to install them are skipped.
+fn parse_app_versions(resp: &str) -> Result<Vec<BitcoinAppV2>, String> {
+    let apps: AppsV1 = serde_json::from_str(resp).map_err(|e| {
+        format!(
+            "Error when deserializing response into list of app versions: {}",
+            e
+        )
+    })?;
+    let mut versions: Vec<BitcoinAppV2> = apps
+        .application_versions
+        .into_iter()
+        .filter_map(|app| serde_json::from_value::<AppVersionV1>(app).ok())
+        .map(BitcoinAppV2::from)
+        .collect();
+    versions.sort_by(|a, b| {
+        let parse = |app: &BitcoinAppV2| app.version.as_deref().and_then(FirmwareVersion::parse);
+        parse(b).cmp(&parse(a))
+    });
+    Ok(versions)
+}
+
+// Get all the published versions of the app with this name for this device, latest first.
+fn query_app_versions(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+    name: &str,
+) -> Result<Vec<BitcoinAppV2>, Box<dyn error::Error>> {
+    let mut versions = query_all_app_versions(network, device_info)?;
+    versions.retain(|app| app.version_name.eq_ignore_ascii_case(name));
+    Ok(versions)
+}
+
+// Find the pinned version among the published versions of the app, listing the available ones if
+// it's not there.
+fn find_pinned_app(
+    versions: &[BitcoinAppV2],
+    pin: &AppPin,
+    name: &str,
+    device_info: &DeviceInfo,
+) -> Result<BitcoinAppV2, String> {
+    if let Some(app) = versions.iter().find(|app| pin.matches(app)) {
+        return Ok(app.clone());
+    }
+    let available: Vec<&str> = versions
+        .iter()
+        .filter_map(|app| app.version.as_deref())
+        .collect();
+    Err(format!(
+        "The {} app with {} is not available for firmware version {}. Available versions: {}",
+        name,
+        pin,
+        device_info.version,
+        available.join(", ")
+    ))
+}
+
+/// A specific version of an app to install, for reproducible setups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppPin {
+    /// The version of the app, for instance "2.1.3".
+    Version(String),
+    /// The hex-encoded hash of the app, as listed in the catalogue.
+    Hash(String),
+}
+
+impl AppPin {
+    fn matches(&self, app: &BitcoinAppV2) -> bool {
+        match self {
+            Self::Version(version) => app.version.as_deref() == Some(version.as_str()),
+            Self::Hash(hash) => app.hash.eq_ignore_ascii_case(hash),
+        }
+    }
+}
+
+impl fmt::Display for AppPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "version {}", version),
+            Self::Hash(hash) => write!(f, "hash {}", hash),
+        }
+    }
+}
+
+// Get the Bitcoin app information for this pinned version. The latest version is looked up in the
+// catalogue first, and older ones through the v1 API.
+fn pinned_bitcoin_app_info(
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+    is_testnet: bool,
+    pin: &AppPin,
+) -> Result<BitcoinAppV2, Box<dyn error::Error>> {
+    let latest = bitcoin_app_info(network, device_info, is_testnet)?;
+    if pin.matches(&latest) {
+        return Ok(latest);
+    }
+
+    let versions = query_app_versions(network, device_info, &latest.version_name)?;
+    Ok(find_pinned_app(
+        &versions,
+        pin,
+        &latest.version_name,
+        device_info,
+    )?)
+}
+
+/// List all the versions of the Bitcoin app (or the Bitcoin Test app) published for the device's
+/// firmware, latest first.
+pub fn app_versions(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+) -> Result<Vec<CatalogueApp>, Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    let versions = query_app_versions(network, &device_info, lowercase_app_name(is_testnet))?;
+    Ok(versions.into_iter().map(CatalogueApp::from).collect())
+}
+
+// Installing and uninstalling an app are both done by connecting through their websocket thing to
+// their HSM. To uninstall an app, Ledger Live "installs" its delete firmware instead of the app's
+// firmware.
+fn install_firmware_via_hsm(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    device_info: &DeviceInfo,
+    bitcoin_app: &BitcoinAppV2,
+    firmware: (&str, &str),
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let install_ws_url = install_url(device_info, bitcoin_app, firmware);
+    query_via_websocket(ledger_api, network, &install_ws_url, on_event)?;
+    Ok(())
+}
+
+fn install_url(
+    device_info: &DeviceInfo,
+    bitcoin_app: &BitcoinAppV2,
+    (firmware, firmware_key): (&str, &str),
+) -> String {
+    // Make sure to properly escape the parameters in the request's parameter.
+    UrlSerializer::new(format!("{}/install?", BASE_SOCKET_URL))
+        .append_pair("targetId", &device_info.target_id.to_string())
+        .append_pair("perso", &bitcoin_app.perso)
+        .append_pair("deleteKey", &bitcoin_app.delete_key)
+        .append_pair("firmware", firmware)
+        .append_pair("firmwareKey", firmware_key)
+        .append_pair("hash", &bitcoin_app.hash)
+        .finish()
+}
+
+/// Install the Bitcoin app (or the Bitcoin Test app) on the device.
+pub fn install_app(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let res = install_app_inner(ledger_api, network, is_testnet, None, on_event);
+    report(res, "Successfully installed the app.", on_event)
+}
+
+/// Install this specific version of the Bitcoin app (or the Bitcoin Test app) on the device. Fails
+/// if this version isn't available for the device's firmware.
+pub fn install_app_version(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    pin: &AppPin,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let res = install_app_inner(ledger_api, network, is_testnet, Some(pin), on_event);
+    report(
+        res,
+        &format!("Successfully installed the app with {}.", pin),
+        on_event,
+    )
+}
+
+fn install_app_inner(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    pin: Option<&AppPin>,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    // Refuse early if the device isn't ready, before prompting to list the apps.
+    let device_info = device_info(ledger_api)?;
+
+    // Then make sure it's not already installed.
+    let apps = query_installed_apps(ledger_api, on_event)?;
+    if has_bitcoin_app(&apps, is_testnet) {
+        //return Err("Bitcoin app already installed. Use the update command to update it.".into());
+    }
+
+    let bitcoin_app = match pin {
+        Some(pin) => pinned_bitcoin_app_info(network, &device_info, is_testnet, pin)?,
+        None => bitcoin_app_info(network, &device_info, is_testnet)?,
+    };
+    check_free_space(&device_info, &apps, &bitcoin_app)?;
+    on_event(Event::ConnectingToHsm("install the app".to_string()));
+    install_firmware_via_hsm(
+        ledger_api,
+        network,
+        &device_info,
+        &bitcoin_app,
+        (&bitcoin_app.firmware, &bitcoin_app.firmware_key),
+        on_event,
+    )
+    .map_err(|e| {
+        with_context(
+            e,
+            "Got an error when installing Bitcoin app from Ledger's remote HSM",
+        )
+    })?;
+    Ok(())
+}
+
+/// Uninstall the Bitcoin app (or the Bitcoin Test app) from the device.
+pub fn uninstall_app(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let res = uninstall_app_inner(ledger_api, network, is_testnet, on_event);
+    report(res, "Successfully uninstalled the app.", on_event)
+}
+
+fn uninstall_app_inner(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    if !is_app_installed(ledger_api, is_testnet, on_event)? {
+        return Err("Bitcoin app is not installed".into());
+    }
+
+    let bitcoin_app = bitcoin_app_info(network, &device_info, is_testnet)?;
+    on_event(Event::ConnectingToHsm("uninstall the app".to_string()));
+    install_firmware_via_hsm(
+        ledger_api,
+        network,
+        &device_info,
+        &bitcoin_app,
+        (&bitcoin_app.delete, &bitcoin_app.delete_key),
+        on_event,
+    )
+    .map_err(|e| {
+        with_context(
+            e,
+            "Got an error when uninstalling Bitcoin app from Ledger's remote HSM",
+        )
+    })?;
+    Ok(())
+}
+
+/// Update the Bitcoin app (or the Bitcoin Test app) on the device to the latest version available
+/// for its firmware. Like Ledger Live, this uninstalls the app and installs the new version.
+pub fn update_app(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    is_testnet: bool,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    let res = uninstall_app_inner(ledger_api, network, is_testnet, on_event)
+        .and_then(|_| install_app_inner(ledger_api, network, is_testnet, None, on_event));
+    report(res, "Successfully updated the app.", on_event)
+}
+
+/// How an installed app compares to Ledger's catalogue of apps for the device's firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditStatus {
+    /// The app is the latest version published by Ledger.
+    UpToDate,
+    /// The app is a previous version published by Ledger.
+    Outdated,
+    /// The app doesn't match any version published by Ledger.
+    HashMismatch,
+    /// There is no app with this name in the catalogue.
+    Unknown,
+}
+
+impl AuditStatus {
+    /// Whether this deserves the attention of the user.
+    pub fn is_anomaly(&self) -> bool {
+        *self != Self::UpToDate
+    }
+}
+
+impl fmt::Display for AuditStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Outdated => write!(f, "outdated"),
+            Self::HashMismatch => write!(f, "doesn't match any version published by Ledger"),
+            Self::Unknown => write!(f, "not in Ledger's catalogue"),
+        }
+    }
+}
+
+/// The result of the audit of an installed app.
+#[derive(Debug, Clone)]
+pub struct AppAudit {
+    pub name: String,
+    pub status: AuditStatus,
+}
+
+// Whether this published version is the installed app.
+fn is_published_version(app: &InstalledApp, published: &BitcoinAppV2) -> bool {
+    published.version_name.eq_ignore_ascii_case(&app.name)
+        && published.hash.eq_ignore_ascii_case(&hex::encode(&app.hash))
+}
+
+/// Compare the installed apps to the catalogue, which contains the latest version of each app, and
+/// to the history of all the versions published for the device's firmware.
+pub fn audit_apps(
+    apps: &[InstalledApp],
+    catalogue: &[BitcoinAppV2],
+    history: &[BitcoinAppV2],
+) -> Vec<AppAudit> {
+    apps.iter()
+        .map(|app| {
+            let latest = catalogue
+                .iter()
+                .find(|c| c.version_name.eq_ignore_ascii_case(&app.name));
+            let status = match latest {
+                None => AuditStatus::Unknown,
+                Some(c) if is_published_version(app, c) => AuditStatus::UpToDate,
+                Some(_) if history.iter().any(|h| is_published_version(app, h)) => {
+                    AuditStatus::Outdated
+                }
+                Some(_) => AuditStatus::HashMismatch,
+            };
+            AppAudit {
+                name: app.name.clone(),
+                status,
+            }
+        })
+        .collect()
+}
+
+// The history of the versions is only needed to audit the apps which aren't the latest version.
+fn needs_version_history(apps: &[InstalledApp], catalogue: &[BitcoinAppV2]) -> bool {
+    audit_apps(apps, catalogue, &[])
+        .iter()
+        .any(|a| a.status == AuditStatus::HashMismatch)
+}
+
+/// Check every installed app against Ledger's catalogue of apps for the device's firmware.
+pub fn audit(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<Vec<AppAudit>, Box<dyn error::Error>> {
+    let res = audit_inner(ledger_api, network, on_event);
+    report(res, "Audited the installed apps.", on_event)
+}
+
+fn audit_inner(
+    ledger_api: &impl Transport,
+    network: &dyn Network,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<Vec<AppAudit>, Box<dyn error::Error>> {
+    let device_info = device_info(ledger_api)?;
+    let apps = query_installed_apps(ledger_api, on_event)?;
+    let resp_apps = network
+        .get(&apps_by_target_url(
+            device_info.target_id,
+            &device_info.version,
+        ))
+        .map_err(|e| {
+            format!(
+                "Error when querying the catalogue of apps for this device: {}",
+                e
+            )
+        })?;
+    let catalogue = parse_app_catalogue(&resp_apps)?;
+
+    let history = if needs_version_history(&apps, &catalogue) {
+        query_all_app_versions(network, &device_info)?
+    } else {
+        Vec::new()
+    };
+    Ok(audit_apps(&apps, &catalogue, &history))
+}
+
+/// An app available in Ledger's catalogue for a device model and firmware version.
+#[derive(Debug, Clone)]
+pub struct CatalogueApp {
+    pub name: String,
+    pub version: Option<String>,
+    /// Size of the app, in bytes.
+    pub size: Option<u64>,
+    /// Hex-encoded hash of the app.
+    pub hash: String,
+    /// The kind of app, for instance "currency", "plugin" or "tool".
+    pub category: Option<String>,
+}
+
+impl From<BitcoinAppV2> for CatalogueApp {
+    fn from(app: BitcoinAppV2) -> Self {
+        Self {
+            name: app.version_name,
+            version: app.version,
+            size: app.bytes,
+            hash: app.hash,
+            category: app.app_type,
+        }
+    }
+}
+
+/// Get all the apps available for a device with this target id running this firmware version,
+/// sorted by name. This doesn't need a device to be connected.
+pub fn app_catalogue(
+    network: &dyn Network,
+    target_id: u32,
+    firmware_version: &str,
+) -> Result<Vec<CatalogueApp>, Box<dyn error::Error>> {
+    let resp_apps = network
+        .get(&apps_by_target_url(target_id, firmware_version))
+        .map_err(|e| format!("Error when querying the catalogue of apps: {}", e))?;
+    let mut apps: Vec<CatalogueApp> = parse_app_catalogue(&resp_apps)?
+        .into_iter()
+        .map(CatalogueApp::from)
+        .collect();
+    apps.sort_by_key(|app| app.name.to_lowercase());
+    Ok(apps)
+}
+
+// Whether the app with this name, matched case-insensitively, is already running. Errors if another
+// app is running, as it has to be quit first. Devices which don't tell which app is running are
+// assumed to be on the dashboard.
+fn is_app_running(ledger_api: &impl Transport, name: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+    let answer = ledger_api.exchange(&GET_APP_AND_VERSION_COMMAND)?;
+    // The dashboard of older firmwares doesn't know this command. Any other failure, for instance
+    // because the device is locked, is an error.
+    let unsupported = [
+        StatusCode::CLA_NOT_SUPPORTED,
+        StatusCode::INS_NOT_SUPPORTED,
+        StatusCode::UNKNOWN_APDU,
+    ];
+    if unsupported.iter().any(|s| *s as u16 == answer.retcode()) {
+        return Ok(false);
+    }
+
+    let running_app = RunningApp::from_answer(&answer)?;
+    if running_app.is_dashboard() {
+        Ok(false)
+    } else if running_app.name.as_bytes().eq_ignore_ascii_case(name) {
+        Ok(true)
+    } else {
+        Err(format!(
+            "Another app ({}) is open on the device, quit it first",
+            running_app.name
+        )
+        .into())
+    }
+}
+
+/// Open the app with this name on the device. The user might have to confirm on the device.
+/// Does nothing if it's already open, errors if another app is open.
+pub fn open_app(ledger_api: &impl Transport, name: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    if is_app_running(ledger_api, name)? {
+        return Ok(());
+    }
+
+    let mut command = OPEN_APP_COMMAND_TEMPLATE;
+    command.data = name;
+
+    let resp = ledger_api
+        .exchange(&command)
+        .map_err(|e| format!("Error opening app: {}", e))?;
+    if resp.retcode() != StatusCode::OK as u16 {
+        return Err(format!("Error opening app. Ledger response: {:#x?}", resp).into());
+    }
+    Ok(())
+}
+
+/// Open the installed app with this name, which is matched case-insensitively. The user might have
+/// to confirm on the device.
+pub fn open_installed_app(
+    ledger_api: &impl Transport,
+    name: &str,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<(), Box<dyn error::Error>> {
+    // Apps can't be listed while one is running.
+    if is_app_running(ledger_api, name.as_bytes())? {
+        return Ok(());
+    }
+
+    let apps = query_installed_apps(ledger_api, on_event)?;
+    let app = apps
+        .iter()
+        .find(|app| app.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<_> = apps.iter().map(|app| app.name.as_str()).collect();
+            format!(
+                "No app named '{}' is installed. Installed apps: {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+
+    on_event(Event::WaitingForConfirmation(format!(
+        "Opening {} on your Ledger",
+        app.name
+    )));
+    open_app(ledger_api, app.name.as_bytes())
+}
+
+/// Quit the app currently running on the device, going back to the dashboard.
+pub fn quit_app(ledger_api: &impl Transport) -> Result<(), Box<dyn error::Error>> {
+    let resp = ledger_api
+        .exchange(&QUIT_APP_COMMAND)
+        .map_err(|e| format!("Error quitting app: {}", e))?;
+    if resp.retcode() != StatusCode::OK as u16 {
+        return Err(format!(
+            "Error quitting app. Status: {}",
+            StatusCode::describe(resp.retcode())
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANO_S: u32 = 0x31100004;
+    const NANO_S_PLUS: u32 = 0x33100004;
+
+    fn device(target_id: u32) -> DeviceInfo {
+        DeviceInfo {
+            target_id,
+            version: "1.1.1".to_string(),
+            flags: vec![0; 4],
+            is_bootloader: false,
+            is_osu: false,
+            se_version: None,
+            se_target_id: target_id,
+            mcu_version: None,
+        }
+    }
+
+    fn installed_app(name: &str, blocks: u16, flags: u16) -> InstalledApp {
+        InstalledApp {
+            name: name.to_string(),
+            hash: vec![0; 32],
+            hash_code_data: vec![0; 32],
+            blocks,
+            flags,
+        }
+    }
+
+    fn catalogue_app(version: &str, hash: &str, bytes: Option<u64>) -> BitcoinAppV2 {
+        BitcoinAppV2 {
+            version_name: "Bitcoin".to_string(),
+            perso: "perso_11".to_string(),
+            delete: "delete".to_string(),
+            delete_key: "delete_key".to_string(),
+            firmware: "firmware".to_string(),
+            firmware_key: "firmware_key".to_string(),
+            hash: hash.to_string(),
+            bytes,
+            version: Some(version.to_string()),
+            app_type: None,
+        }
+    }
+
+    // Answers every command with the next of these answers, each with its status.
+    struct MockTransport(std::cell::RefCell<Vec<Result<&'static str, &'static str>>>);
+
+    impl Transport for MockTransport {
+        fn exchange<I: std::ops::Deref<Target = [u8]>>(
+            &self,
+            _command: &APDUCommand<I>,
+        ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+            let answer = self.0.borrow_mut().remove(0)?;
+            Ok(APDUAnswer::from_answer(hex::decode(answer)?)?)
+        }
+    }
+
+    #[test]
+    fn app_running() {
+        // "Bitcoin" 2.1.3, and the dashboard.
+        let bitcoin = Ok("0107426974636f696e05322e312e3301029000");
+        let dashboard = Ok("0105424f4c4f5305312e312e3101009000");
+        let cases = [
+            (bitcoin, Ok(true)),
+            (dashboard, Ok(false)),
+            (Ok("6d00"), Ok(false)),
+            (Ok("6e00"), Ok(false)),
+            (
+                Ok("5515"),
+                Err("Error getting the running app. Status: LOCKED_DEVICE (0x5515)"),
+            ),
+            (Err("Device disconnected"), Err("Device disconnected")),
+        ];
+        for (answer, expected) in cases {
+            let ledger_api = MockTransport(vec![answer].into());
+            let res = is_app_running(&ledger_api, b"bitcoin").map_err(|e| e.to_string());
+            assert_eq!(res, expected.map_err(str::to_string), "{:?}", answer);
+        }
+
+        // Another app has to be quit first.
+        let ledger_api = MockTransport(vec![Ok("0108457468657265756d05312e302e30009000")].into());
+        assert_eq!(
+            is_app_running(&ledger_api, b"bitcoin")
+                .unwrap_err()
+                .to_string(),
+            "Another app (Ethereum) is open on the device, quit it first"
+        );
+    }
+
+    #[test]
+    fn app_flags() {
+        assert_eq!(
+            AppFlags(0x8a51).names(),
+            vec![
+                "issuer",
+                "derive master",
+                "global pin",
+                "bolos settings",
+                "library",
+                "enabled"
+            ]
+        );
+        // Unknown bits are ignored.
+        assert_eq!(AppFlags(0x6000).names(), Vec::<&str>::new());
+        assert!(AppFlags(0x0405).contains(AppFlags::CUSTOM_CA | AppFlags::SIGNED));
+        assert!(!AppFlags(0x0405).contains(AppFlags::CUSTOM_CA | AppFlags::DEBUG));
+
+        // Flags of an installed app, and whether it's official and in debug mode.
+        let cases = [
+            (0x8005, true, false),
+            (0x8405, false, false),
+            (0x8004, false, false),
+            (0x0084, false, true),
+            (0x8085, true, true),
+        ];
+        for (flags, is_official, is_debug) in cases {
+            let app = installed_app("Bitcoin", 1, flags);
+            assert_eq!(app.is_official(), is_official, "{:#06x}", flags);
+            assert_eq!(app.is_debug(), is_debug, "{:#06x}", flags);
+        }
+    }
+
+    #[test]
+    fn required_space() {
+        // Apps use whole blocks, which are much bigger on the Nano S than on more recent models.
+        let cases = [
+            (NANO_S, 5000, 8192),
+            (NANO_S, 4096, 4096),
+            (NANO_S_PLUS, 5000, 5024),
+            (NANO_S_PLUS, 4096, 4096),
+        ];
+        for (target_id, app_size, required) in cases {
+            let storage = StorageReport::new(&device(target_id), &[]).unwrap();
+            assert_eq!(
+                storage.required_space(app_size),
+                required,
+                "{:x}",
+                target_id
+            );
+        }
+    }
+
+    #[test]
+    fn free_space_estimate() {
+        let apps = [
+            installed_app("Bitcoin", 10, 0),
+            installed_app("Ethereum", 20, 0),
+        ];
+        let storage = StorageReport::new(&device(NANO_S), &apps).unwrap();
+        assert_eq!(storage.total, 320 * 1024);
+        assert_eq!(storage.used, 30 * 4096);
+        assert_eq!(storage.free, 320 * 1024 - 30 * 4096);
+
+        // The firmware uses some storage too, the apps can't be relied on not to exceed the total.
+        let storage = StorageReport::new(&device(NANO_S), &[installed_app("Big", 100, 0)]).unwrap();
+        assert_eq!(storage.free, 0);
+
+        assert!(StorageReport::new(&device(0x12340000), &apps).is_none());
+    }
+
+    #[test]
+    fn suggested_removals() {
+        let apps = [
+            installed_app("Small", 10, 0),
+            installed_app("Big", 30, 0),
+            installed_app("Medium", 20, 0),
+        ];
+        let storage = StorageReport::new(&device(NANO_S), &apps).unwrap();
+        assert_eq!(storage.suggest_removals(0), Vec::<&str>::new());
+        assert_eq!(storage.suggest_removals(4096), vec!["Big"]);
+        assert_eq!(storage.suggest_removals(35 * 4096), vec!["Big", "Medium"]);
+        assert_eq!(
+            storage.suggest_removals(60 * 4096),
+            vec!["Big", "Medium", "Small"]
+        );
+        // Uninstalling everything wouldn't be enough.
+        assert_eq!(storage.suggest_removals(61 * 4096), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn not_enough_space() {
+        // 20 KB left, and the app needs 13 blocks.
+        let apps = [installed_app("Big", 45, 0), installed_app("Small", 30, 0)];
+        let device = device(NANO_S);
+        let err =
+            check_free_space(&device, &apps, &catalogue_app("2.1.3", "", Some(50000))).unwrap_err();
+        assert_eq!(
+            err,
+            "Not enough storage left on the device to install the app: it needs 52 KB but at most 20 KB are free. Consider uninstalling Big."
+        );
+
+        assert!(check_free_space(&device, &apps, &catalogue_app("2.1.3", "", Some(20000))).is_ok());
+        // Without the size of the app, the HSM is left to decide.
+        assert!(check_free_space(&device, &apps, &catalogue_app("2.1.3", "", None)).is_ok());
+    }
+
+    #[test]
+    fn hsm_queries() {
+        // A message from the HSM, and the events it gives or None if it's an error.
+        let cases: &[(&str, Option<&[&str]>)] = &[
+            (r#"{"query":"new-query","nonce":1}"#, None),
+            (
+                r#"{"query":"new-query"}"#,
+                Some(&[r#"HsmInfo("{\"query\":\"new-query\"}")"#]),
+            ),
+            (
+                r#"{"query":"device-permission-requested"}"#,
+                Some(&[
+                    r#"WaitingForConfirmation("Ledger's remote HSM is requesting a permission on your device")"#,
+                ]),
+            ),
+            (
+                r#"{"query":"device-permission-granted"}"#,
+                Some(&["PermissionGranted"]),
+            ),
+            (
+                r#"{"query":"bulk-progress","progress":0.5}"#,
+                Some(&[r#"HsmInfo("{\"query\":\"bulk-progress\",\"progress\":0.5}")"#]),
+            ),
+            (
+                r#"{"query":"warning","data":"low battery"}"#,
+                Some(&[r#"HsmWarning("{\"query\":\"warning\",\"data\":\"low battery\"}")"#]),
+            ),
+            (r#"{"query":"exchange","nonce":1}"#, None),
+            (r#"{"query":"error","data":"oops"}"#, None),
+        ];
+
+        for (text, expected) in cases {
+            let mut events = Vec::new();
+            let res = HsmSession::default()
+                .on_message(tungstenite::Message::Text(text.to_string()), &mut |e| {
+                    events.push(format!("{:?}", e))
+                });
+            match expected {
+                Some(expected) => {
+                    assert!(matches!(res, Ok(HsmStep::Read)), "{}: {:?}", text, res);
+                    assert_eq!(&events, expected, "{}", text);
+                }
+                None => assert!(res.is_err(), "{}", text),
+            }
+        }
+    }
+
+    // A version as listed by the v1 API.
+    fn app_version(version: &str, hash: &str) -> serde_json::Value {
+        serde_json::json!({
+            "name": "Bitcoin",
+            "version": version,
+            "perso": "perso_11",
+            "delete": "delete",
+            "delete_key": "delete_key",
+            "firmware": "firmware",
+            "firmware_key": "firmware_key",
+            "hash": hash,
+            "bytes": 65536,
+        })
+    }
+
+    #[test]
+    fn app_versions() {
+        let mut incomplete = app_version("2.0.6", "cc");
+        incomplete["perso"] = serde_json::Value::Null;
+        let resp = serde_json::json!({
+            "application_versions": [
+                app_version("2.1.2", "aa"),
+                incomplete,
+                app_version("2.1.3", "bb"),
+            ]
+        });
+
+        // The incomplete version is left out, the others are sorted latest first.
+        let versions = parse_app_versions(&resp.to_string()).unwrap();
+        let versions: Vec<_> = versions.iter().map(|app| app.version.as_deref()).collect();
+        assert_eq!(versions, [Some("2.1.3"), Some("2.1.2")]);
+
+        let err = parse_app_versions("{}").unwrap_err();
+        assert!(
+            err.starts_with("Error when deserializing response"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn pinned_app() {
+        let versions = [
+            catalogue_app("2.1.3", "AABB", None),
+            catalogue_app("2.1.2", "ccdd", None),
+        ];
+        let device = device(NANO_S_PLUS);
+        let find = |pin: AppPin| {
+            find_pinned_app(&versions, &pin, "Bitcoin", &device).map(|app| app.hash.clone())
+        };
+
+        assert_eq!(
+            find(AppPin::Version("2.1.2".to_string())),
+            Ok("ccdd".to_string())
+        );
+        assert_eq!(
+            find(AppPin::Hash("aabb".to_string())),
+            Ok("AABB".to_string())
+        );
+        assert_eq!(
+            find(AppPin::Hash("CCDD".to_string())),
+            Ok("ccdd".to_string())
+        );
+        assert_eq!(
+            find(AppPin::Version("2.0.6".to_string())),
+            Err(
+                "The Bitcoin app with version 2.0.6 is not available for firmware version \
+                 1.1.1. Available versions: 2.1.3, 2.1.2"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            find(AppPin::Hash("eeff".to_string())),
+            Err(
+                "The Bitcoin app with hash eeff is not available for firmware version 1.1.1. \
+                 Available versions: 2.1.3, 2.1.2"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn mcu_update_plan() {
+        let cases = [
+            ("1.16", "1.16", McuUpdate::Mcu("2.30".to_string())),
+            ("1.16.0", "1.16", McuUpdate::Mcu("2.30".to_string())),
+            ("1.16", "1.16.0", McuUpdate::Mcu("2.30".to_string())),
+            ("1.16", "none", McuUpdate::Mcu("2.30".to_string())),
+            ("1.16", "1.18", McuUpdate::Bootloader("1.18".to_string())),
+            (
+                "1.16.0",
+                "1.16.1",
+                McuUpdate::Bootloader("1.16.1".to_string()),
+            ),
+        ];
+        for (bootloader, from_bootloader_version, expected) in cases {
+            let device_info = DeviceInfo {
+                version: bootloader.to_string(),
+                is_bootloader: true,
+                ..device(NANO_S_PLUS)
+            };
+            let mcu = McuVersion {
+                id: 12,
+                name: "2.30".to_string(),
+                from_bootloader_version: from_bootloader_version.to_string(),
+            };
+            let (_, update) = plan_mcu_update(&device_info, mcu);
+            assert_eq!(
+                update, expected,
+                "{} from {}",
+                bootloader, from_bootloader_version
+            );
+        }
+    }
+}
@@ -1,372 +1,34 @@
-use form_urlencoded::Serializer as UrlSerializer;
-use ledger_apdu::APDUCommand;
-use ledger_transport_hidapi::{hidapi::HidApi, TransportNativeHID};
-use serde_derive::Deserialize;
-
-use std::{env, error, process, str};
-
-// https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/getVersion.ts#L6
-const GET_VERSION_COMMAND: APDUCommand<&[u8]> = APDUCommand {
-    cla: 0xe0,
-    ins: 0x01,
-    p1: 0x00,
-    p2: 0x00,
-    data: &[],
+use ledger_installer::{
+    app_catalogue, app_versions, audit,
+    cache::CachingNetwork,
+    firmware_status, genuine_check, install_app, install_app_version, list_installed_apps,
+    network::{LiveNetwork, Network},
+    open_app, open_installed_app, quit_app,
+    session::{
+        Recorder, RecordingNetwork, RecordingTransport, ReplayNetwork, ReplayTransport, Replayer,
+        SessionEvent,
+    },
+    transport::{wait_for_device, ReconnectingTransport, Transport},
+    uninstall_app, update_app, update_mcu, AppPin, BulkProgress, DeviceInfo, Event, RunningApp,
+    StorageReport,
 };
-
-// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L5
-const LIST_APPS_COMMAND: APDUCommand<&[u8]> = APDUCommand {
-    cla: 0xe0,
-    ins: 0xde,
-    p1: 0x00,
-    p2: 0x00,
-    data: &[],
-};
-
-// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L47
-const CONTINUE_LIST_APPS_COMMAND: APDUCommand<&[u8]> = APDUCommand {
-    cla: 0xe0,
-    ins: 0xdf,
-    p1: 0x00,
-    p2: 0x00,
-    data: &[],
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
-// https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/hw/openApp.ts#L3
-const OPEN_APP_COMMAND_TEMPLATE: APDUCommand<&[u8]> = APDUCommand {
-    cla: 0xe0,
-    ins: 0xd8,
-    p1: 0x00,
-    p2: 0x00,
-    data: &[],
-};
-
-const LIVE_COMMON_VERSION: &str = "34.0.0";
-const PROVIDER: u32 = 1; // TODO: make it possible to set it.
-const BASE_API_V1_URL: &str = "https://manager.api.live.ledger.com/api";
-const BASE_API_V2_URL: &str = "https://manager.api.live.ledger.com/api/v2";
-const BASE_SOCKET_URL: &str = "wss://scriptrunner.api.live.ledger.com/update";
-
-#[derive(Debug, Clone, Copy)]
-enum StatusCode {
-    //ACCESS_CONDITION_NOT_FULFILLED = 0x9804,
-    //ALGORITHM_NOT_SUPPORTED = 0x9484,
-    //CLA_NOT_SUPPORTED = 0x6e00,
-    //CODE_BLOCKED = 0x9840,
-    //CODE_NOT_INITIALIZED = 0x9802,
-    //COMMAND_INCOMPATIBLE_FILE_STRUCTURE = 0x6981,
-    //CONDITIONS_OF_USE_NOT_SATISFIED = 0x6985,
-    //CONTRADICTION_INVALIDATION = 0x9810,
-    //CONTRADICTION_SECRET_CODE_STATUS = 0x9808,
-    //CUSTOM_IMAGE_BOOTLOADER = 0x662f,
-    //CUSTOM_IMAGE_EMPTY = 0x662e,
-    //FILE_ALREADY_EXISTS = 0x6a89,
-    //FILE_NOT_FOUND = 0x9404,
-    //GP_AUTH_FAILED = 0x6300,
-    //HALTED = 0x6faa,
-    //INCONSISTENT_FILE = 0x9408,
-    //INCORRECT_DATA = 0x6a80,
-    //INCORRECT_LENGTH = 0x6700,
-    //INCORRECT_P1_P2 = 0x6b00,
-    //INS_NOT_SUPPORTED = 0x6d00,
-    //DEVICE_NOT_ONBOARDED = 0x6d07,
-    //DEVICE_NOT_ONBOARDED_2 = 0x6611,
-    //INVALID_KCV = 0x9485,
-    //INVALID_OFFSET = 0x9402,
-    //LICENSING = 0x6f42,
-    //LOCKED_DEVICE = 0x5515,
-    //MAX_VALUE_REACHED = 0x9850,
-    //MEMORY_PROBLEM = 0x9240,
-    //MISSING_CRITICAL_PARAMETER = 0x6800,
-    //NO_EF_SELECTED = 0x9400,
-    //NOT_ENOUGH_MEMORY_SPACE = 0x6a84,
-    OK = 0x9000,
-    //PIN_REMAINING_ATTEMPTS = 0x63c0,
-    //REFERENCED_DATA_NOT_FOUND = 0x6a88,
-    //SECURITY_STATUS_NOT_SATISFIED = 0x6982,
-    //TECHNICAL_PROBLEM = 0x6f00,
-    //UNKNOWN_APDU = 0x6d02,
-    //USER_REFUSED_ON_DEVICE = 0x5501,
-    //NOT_ENOUGH_SPACE = 0x5102,
-}
-
-// NOTE: MCU target id is always == target_id in Ledger Live
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
-struct DeviceInfo {
-    pub target_id: u32,
-    pub version: String,
-    pub flags: Vec<u8>,
-    pub is_bootloader: bool,
-    pub se_version: Option<String>,
-    pub se_target_id: u32,
-    pub mcu_version: Option<String>,
-}
-
-impl DeviceInfo {
-    /// Query information about this device.
-    ///
-    /// Adapted from https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/parseGetVersionResponse.ts
-    pub fn new(ledger_api: &TransportNativeHID) -> Result<Self, Box<dyn error::Error>> {
-        let ver_answer = ledger_api.exchange(&GET_VERSION_COMMAND)?;
-        let data = ver_answer.data();
-        let mut i = 0;
-
-        if data.len() < 5 {
-            return Err("Not enough data".into());
-        }
-        let target_id = u32::from_be_bytes(data[i..i + 4].try_into()?);
-        i += 4;
-        let raw_ver_len = data[i] as usize;
-        i += 1;
-
-        if data.len() < i + raw_ver_len + 1 {
-            return Err("Not enough data".into());
-        }
-        let raw_ver = &data[i..i + raw_ver_len];
-        i += raw_ver_len;
-        let version = str::from_utf8(raw_ver)?;
-        let flags_len = data[i] as usize;
-        i += 1;
-
-        if data.len() < i + flags_len {
-            return Err("Not enough data".into());
-        }
-        let flags = &data[i..i + flags_len];
-        i += flags_len;
-
-        let is_bootloader = (target_id & 4026531840) != 805306368;
-        Ok(if is_bootloader {
-            if data.len() < i + 1 {
-                return Err("Not enough data".into());
-            }
-            let part1_len = data[i] as usize;
-            i += 1;
-
-            if data.len() < i + part1_len {
-                return Err("Not enough data".into());
-            }
-            let part1 = &data[i..i + part1_len];
-            i += part1_len;
-
-            if part1_len >= 5 {
-                let se_version = str::from_utf8(part1).unwrap();
-
-                if data.len() < i + 1 {
-                    return Err("Not enough data".into());
-                }
-                let part2_len = data[i] as usize;
-                i += 1;
-
-                if data.len() < i + part2_len {
-                    return Err("Not enough data".into());
-                }
-                let part2 = &data[i..i + part2_len];
-                //i += part2_len;
-                let se_target_id = u32::from_be_bytes(part2.try_into().unwrap());
-
-                Self {
-                    target_id,
-                    version: version.to_string(),
-                    flags: flags.to_vec(),
-                    is_bootloader,
-                    se_version: Some(se_version.to_string()),
-                    se_target_id,
-                    mcu_version: None,
-                }
-            } else {
-                let se_target_id = u32::from_be_bytes(part1.try_into().unwrap());
-
-                Self {
-                    target_id,
-                    version: version.to_string(),
-                    flags: flags.to_vec(),
-                    is_bootloader,
-                    se_version: None,
-                    se_target_id,
-                    mcu_version: None,
-                }
-            }
-        } else {
-            if data.len() < i + 1 {
-                return Err("Not enough data".into());
-            }
-            let mcu_len = data[i] as usize;
-            i += 1;
-
-            if data.len() < i + mcu_len {
-                return Err("Not enough data".into());
-            }
-            let mcu = &data[i..i + mcu_len];
-            //i += mcu_len;
-            let mcu = if mcu[mcu.len() - 1] == 0 {
-                &mcu[..mcu.len() - 1]
-            } else {
-                mcu
-            };
-            let mcu_version = str::from_utf8(mcu).unwrap();
-
-            //let osu_str = b"-osu";
-            //if raw_ver.windows(osu_str.len()).any(|w| w == osu_str) {}
-            //TODO. See https://github.com/LedgerHQ/ledger-live/blob/dcbda65e65ead4014e767778da6022b78d8eddad/libs/ledgerjs/packages/devices/src/index.ts#L3-L156
-
-            Self {
-                target_id,
-                version: version.to_string(),
-                flags: flags.to_vec(),
-                is_bootloader,
-                se_version: Some(version.to_string()),
-                se_target_id: target_id,
-                mcu_version: Some(mcu_version.to_string()),
-            }
-        })
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-struct InstalledApp {
-    name: String,
-    hash: Vec<u8>,
-    hash_code_data: Vec<u8>,
-    blocks: u16,
-    flags: u16,
-}
+// How long to wait for the device to come back when it re-enumerates, for instance after opening
+// an app.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
-enum HsmMessageData {
-    Command(String),
-    CommandList(Vec<String>),
-}
+// How long to use the cached Manager API responses for by default.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
 
-#[derive(Debug, Clone, Deserialize)]
-struct HsmMessage {
-    pub query: String,
-    pub nonce: u32,
-    pub data: Option<HsmMessageData>,
-}
-
-fn deser_apdu_command(hex_str: &str) -> Result<APDUCommand<Vec<u8>>, Box<dyn error::Error>> {
-    let bytes = hex::decode(hex_str)?;
-    if bytes.len() < 5 {
-        return Err("Invalid command".into());
-    }
-
-    let (cla, ins, p1, p2, data_len) = (bytes[0], bytes[1], bytes[2], bytes[3], bytes[4] as usize);
-    if bytes.len() != 5 + data_len {
-        return Err("Invalid command".into());
-    }
-
-    Ok(APDUCommand {
-        cla,
-        ins,
-        p1,
-        p2,
-        data: bytes[5..].to_vec(),
-    })
-}
-
-// Some actions, such as installing apps or upgrading the firmware, are done in Ledger Live by
-// opening a socket so a remote server communicates directly with the Ledger. It appears to be
-// talking to an HSM up there which would manage sensitive actions.
-// Parameters are passed directly in the url. Don't forget to escape the necessary characters!
-fn query_via_websocket(
-    ledger_api: &TransportNativeHID,
-    url: &str,
-) -> Result<(), Box<dyn error::Error>> {
-    let (mut socket, _) = tungstenite::connect(url)?;
-
-    // https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/socket/index.ts#L95
-    loop {
-        let msg = socket.read()?;
-        match msg {
-            // It appears they only exchange JSON text messages.
-            tungstenite::Message::Text(text) => {
-                let msg: HsmMessage = serde_json::from_str(&text)?;
-
-                // The dance is usually:
-                // - first the HSM sends a few standalone commands;
-                // - then it sends a bunch in bulk;
-                // - finally it sends a success.
-                if msg.query == "exchange" {
-                    let command_hex = match msg.data {
-                        Some(HsmMessageData::Command(h)) => h,
-                        _ => return Err("A single command is expected in 'exchange' mode.".into()),
-                    };
-                    let command = deser_apdu_command(&command_hex)?;
-
-                    // NOTE: the HSM expects only the data, not the last two bytes of the raw
-                    // response (the status) in the "data" field below.
-                    let resp = ledger_api.exchange(&command)?;
-                    let response = if resp.retcode() == StatusCode::OK as u16 {
-                        "success"
-                    } else {
-                        eprintln!(
-                            "Error when installing app. Error code: {:#02x}. Resp: {:?}.",
-                            resp.retcode(),
-                            resp
-                        );
-                        "error"
-                    };
-                    let resp_data = hex::encode(resp.data());
-
-                    let ws_resp = serde_json::json!({
-                        "nonce": msg.nonce,
-                        "response": response,
-                        "data": resp_data,
-                    });
-                    socket.send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))?;
-                } else if msg.query == "bulk" {
-                    // Ledger Live closes the socket immediately after receiving a bulk. It doesn't
-                    // appear to be necessary, on the contrary if we don't we get a clean "success"
-                    // response back. So we might as well do that.
-                    //socket.close(None).unwrap();
-
-                    let commands = match msg.data {
-                        Some(HsmMessageData::CommandList(l)) => l,
-                        _ => return Err("Expecting a list of commands in bulk mode.".into()),
-                    };
-                    for cmd_hex in commands {
-                        if cmd_hex.is_empty() {
-                            continue;
-                        }
-                        let command = deser_apdu_command(&cmd_hex)?;
-                        let _ = ledger_api.exchange(&command)?;
-                    }
-
-                    let ws_resp = serde_json::json!({
-                        "nonce": msg.nonce,
-                        "response": "success",
-                        "data": "",
-                    });
-                    socket.send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))?;
-                } else if msg.query == "success" {
-                    return Ok(());
-                } else if msg.query == "error" {
-                    return Err(
-                        format!("Got an 'error' query on the ws. Full message: {}.", text).into(),
-                    );
-                } else if msg.query == "warning" {
-                    eprintln!("Got a 'warning' query on the ws. Full message: {}.", text);
-                } else {
-                    return Err(format!(
-                        "Got an unsupported query on the ws. Full message: {}.",
-                        text
-                    )
-                    .into());
-                }
-            }
-            _ => {
-                return Err(format!(
-                    "Got an unsupported message type on the ws. Message: {:?}.",
-                    msg
-                )
-                .into())
-            }
-        }
-    }
-}
+// Whether a progress bar is being drawn on the current line.
+static PROGRESS_SHOWN: AtomicBool = AtomicBool::new(false);
 
 // Print on stderr and exit with 1.
 macro_rules! error {
@@ -376,16 +38,27 @@ macro_rules! error {
     }};
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum Command {
     GetInfo,
     GenuineCheck,
+    Audit,
+    Catalog,
+    FirmwareStatus,
+    MainAppVersions,
+    TestAppVersions,
     InstallMainApp,
     UpdateMainApp,
+    UninstallMainApp,
     OpenMainApp,
     InstallTestApp,
     UpdateTestApp,
+    UninstallTestApp,
     OpenTestApp,
+    // Open any installed app by name.
+    Open(String),
+    QuitApp,
+    UpdateMcu,
     UpdateeFirmware,
 }
 
@@ -394,11 +67,27 @@ impl Command {
     pub fn get() -> Option<Self> {
         let is_testnet = env::var("LEDGER_TESTNET").is_ok();
         let cmd_str = env::var("LEDGER_COMMAND").ok()?;
+        Self::parse(&cmd_str, is_testnet)
+    }
 
+    /// Parse a command as passed through the LEDGER_COMMAND env var.
+    pub fn parse(cmd_str: &str, is_testnet: bool) -> Option<Self> {
         if cmd_str == "getinfo" {
             Some(Self::GetInfo)
         } else if cmd_str == "genuinecheck" {
             Some(Self::GenuineCheck)
+        } else if cmd_str == "audit" {
+            Some(Self::Audit)
+        } else if cmd_str == "catalog" {
+            Some(Self::Catalog)
+        } else if cmd_str == "firmware-status" {
+            Some(Self::FirmwareStatus)
+        } else if cmd_str == "appversions" {
+            Some(if is_testnet {
+                Self::TestAppVersions
+            } else {
+                Self::MainAppVersions
+            })
         } else if cmd_str == "installapp" {
             Some(if is_testnet {
                 Self::InstallTestApp
@@ -411,354 +100,578 @@ impl Command {
             } else {
                 Self::UpdateMainApp
             })
+        } else if cmd_str == "uninstallapp" {
+            Some(if is_testnet {
+                Self::UninstallTestApp
+            } else {
+                Self::UninstallMainApp
+            })
         } else if cmd_str == "openapp" {
             Some(if is_testnet {
                 Self::OpenTestApp
             } else {
                 Self::OpenMainApp
             })
+        } else if let Some(name) = cmd_str.strip_prefix("open ") {
+            Some(Self::Open(name.trim().to_string()))
+        } else if cmd_str == "quitapp" {
+            Some(Self::QuitApp)
+        } else if cmd_str == "updatemcu" {
+            Some(Self::UpdateMcu)
         } else if cmd_str == "updatefirm" {
             Some(Self::UpdateeFirmware)
         } else {
             None
         }
     }
-}
 
-fn ledger_api() -> TransportNativeHID {
-    let hid_api = match HidApi::new() {
-        Ok(a) => a,
-        Err(e) => error!("Error initializing HDI api: {}.", e),
-    };
-    match TransportNativeHID::new(&hid_api) {
-        Ok(a) => a,
-        Err(e) => error!("Error connecting to Ledger device: {}.", e),
+    // Whether the command can be served cached responses from the Manager API: it doesn't install
+    // anything nor need Ledger's HSM.
+    fn can_use_cache(&self) -> bool {
+        matches!(
+            self,
+            Self::GetInfo
+                | Self::Audit
+                | Self::Catalog
+                | Self::FirmwareStatus
+                | Self::MainAppVersions
+                | Self::TestAppVersions
+                | Self::OpenMainApp
+                | Self::OpenTestApp
+                | Self::Open(_)
+                | Self::QuitApp
+        )
     }
 }
 
-fn device_info(ledger_api: &TransportNativeHID) -> DeviceInfo {
-    match DeviceInfo::new(&ledger_api) {
-        Ok(i) => i,
-        Err(e) => error!("Error fetching device info: {}. Is the Ledger unlocked?", e),
+// Render the progress of a bulk transfer as a progress bar on stderr.
+fn print_progress(progress: &BulkProgress) {
+    const WIDTH: usize = 40;
+    let filled = (WIDTH * progress.sent)
+        .checked_div(progress.total)
+        .unwrap_or(WIDTH);
+    eprint!(
+        "\r[{}{}] {}/{} commands, {}/{} bytes",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        progress.sent,
+        progress.total,
+        progress.bytes,
+        progress.total_bytes,
+    );
+    let done = progress.sent == progress.total;
+    if done {
+        eprintln!();
     }
+    PROGRESS_SHOWN.store(!done, Ordering::Relaxed);
 }
 
-fn list_installed_apps(
-    ledger_api: &TransportNativeHID,
-) -> Result<Vec<InstalledApp>, Box<dyn error::Error>> {
-    println!("Querying installed applications from your Ledger. You might have to confirm on your device.");
-
-    let mut answer = ledger_api.exchange(&LIST_APPS_COMMAND)?;
-    let mut data = answer.data();
-
-    // See https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/hw/listApps.ts#L9
-    let mut installed_apps = Vec::new();
-    while !data.is_empty() {
-        let mut i = 0;
-        assert_eq!(data[i], 0x01);
-        i += 1;
-
-        while i < data.len() {
-            if data.len() < i + 1 + 2 + 2 + 32 + 32 + 1 {
-                return Err("Not enough data".into());
+// Render the events from an operation on the terminal.
+fn print_event(event: Event) {
+    match event {
+        Event::WaitingForConfirmation(what) => {
+            println!("{}. You might have to confirm on your device.", what)
+        }
+        Event::ConnectingToHsm(what) => println!(
+            "Querying Ledger's remote HSM to {}. You might have to confirm the operation on your device.",
+            what
+        ),
+        Event::DeviceDisconnected => println!("Waiting for your Ledger to be plugged in..."),
+        Event::DeviceLocked => println!("Waiting for your Ledger to be unlocked..."),
+        Event::PermissionGranted => println!("Permission granted on your device."),
+        Event::Progress(progress) => print_progress(&progress),
+        Event::HsmWarning(text) => {
+            eprintln!("Got a 'warning' query on the ws. Full message: {}.", text)
+        }
+        Event::HsmInfo(text) => println!("Message from the HSM: {}.", text),
+        Event::Done(msg) => println!("{}", msg),
+        // The error is returned by the operation and printed by the caller, on its own line.
+        Event::Failed(_) => {
+            if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+                eprintln!();
             }
+        }
+    }
+}
 
-            let len = data[i] as usize;
-            i += 1;
-            let blocks = u16::from_be_bytes(data[i..i + 2].try_into()?);
-            i += 2;
-            let flags = u16::from_be_bytes(data[i..i + 2].try_into()?);
-            i += 2;
-            let hash_code_data = data[i..i + 32].to_vec();
-            i += 32;
-            let hash = data[i..i + 32].to_vec();
-            i += 32;
-            let name_len = data[i] as usize;
-            i += 1;
-
-            if data.len() < i + name_len {
-                return Err("Not enough data".into());
-            }
-            if len != name_len + 70 {
-                return Err("Invalid listApps length data.".into());
-            }
-            let name = str::from_utf8(&data[i..i + name_len])?.to_string();
-            i += name_len;
-
-            installed_apps.push(InstalledApp {
-                name,
-                hash,
-                hash_code_data,
-                blocks,
-                flags,
-            });
+// Connect to the device, reconnecting to it if it re-enumerates. Set the LEDGER_WAIT env var to a
+// number of seconds to wait for the device to be plugged in and unlocked. This is also how long it's
+// waited for when reconnecting.
+fn ledger_api() -> ReconnectingTransport {
+    let mut timeout = RECONNECT_TIMEOUT;
+    if let Ok(wait) = env::var("LEDGER_WAIT") {
+        timeout = match wait.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => error!(
+                "Invalid LEDGER_WAIT value '{}', must be a number of seconds.",
+                wait
+            ),
+        };
+        // The connection is dropped right away to be re-opened below.
+        if let Err(e) = wait_for_device(timeout, &mut print_event) {
+            error!("Error connecting to Ledger device: {}.", e);
         }
+    }
 
-        answer = ledger_api.exchange(&CONTINUE_LIST_APPS_COMMAND)?;
-        data = answer.data();
+    match ReconnectingTransport::new(timeout) {
+        Ok(a) => a,
+        Err(e) => error!("Error connecting to Ledger device: {}.", e),
     }
+}
 
-    Ok(installed_apps)
+fn device_info(ledger_api: &impl Transport) -> DeviceInfo {
+    match DeviceInfo::new(ledger_api) {
+        Ok(i) => i,
+        Err(e) => error!("Error fetching device info: {}. Is the Ledger unlocked?", e),
+    }
 }
 
-fn print_ledger_info(ledger_api: &TransportNativeHID) {
+fn print_ledger_info(ledger_api: &impl Transport) {
+    // Information about the device can only be queried from the dashboard.
+    if let Ok(running_app) = RunningApp::new(ledger_api) {
+        if !running_app.is_dashboard() {
+            error!(
+                "The {} app (version {}) is open on your device. Quit it first, for instance with the quitapp command.",
+                running_app.name, running_app.version
+            );
+        }
+    }
+
     let device_info = device_info(ledger_api);
     println!("Information about the device: {:#?}", device_info);
+    if !device_info.is_bootloader {
+        println!("State of the device: {:#?}", device_info.device_flags());
+    }
+    if device_info.is_osu {
+        println!(
+            "The device is running the OS updater: an upgrade to firmware version {} is in progress.",
+            device_info.version
+        );
+    }
 
-    let apps = match list_installed_apps(&ledger_api) {
+    print_event(Event::WaitingForConfirmation(
+        "Querying installed applications from your Ledger".to_string(),
+    ));
+    let apps = match list_installed_apps(ledger_api) {
         Ok(a) => a,
         Err(e) => error!("Error listing installed applications: {}.", e),
     };
+    // Only list the apps which deserve a closer look if asked to.
+    let suspicious_only = env::var("LEDGER_SUSPICIOUS_APPS").is_ok();
     println!("Installed applications:");
-    for app in apps {
+    for app in &apps {
+        if suspicious_only && app.is_official() && !app.is_debug() {
+            continue;
+        }
         println!("  - {:?}", app);
+        println!("    Flags: {}.", app.app_flags().names().join(", "));
+        if !app.is_official() {
+            println!("    Warning: this app was not installed from an official source.");
+        }
+        if app.is_debug() {
+            println!("    Warning: this app was installed in debug mode.");
+        }
+    }
+
+    if let Some(storage) = StorageReport::new(&device_info, &apps) {
+        println!(
+            "Storage: {} KB used by apps out of {} KB, at most {} KB free.",
+            storage.used / 1024,
+            storage.total / 1024,
+            storage.free / 1024
+        );
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct DeviceVersion {
-    pub id: i64,
+// The device to list the catalogue of apps for, if given through the LEDGER_TARGET_ID and
+// LEDGER_FIRMWARE env vars instead of querying it from the connected device. The target id may be
+// given in hexadecimal with a 0x prefix.
+fn catalog_target() -> Option<(u32, String)> {
+    let (target_id, firmware) = match (env::var("LEDGER_TARGET_ID"), env::var("LEDGER_FIRMWARE")) {
+        (Ok(t), Ok(f)) => (t, f),
+        (Err(_), Err(_)) => return None,
+        _ => error!("LEDGER_TARGET_ID and LEDGER_FIRMWARE must be set together."),
+    };
+    let parsed = match target_id.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => target_id.parse(),
+    };
+    match parsed {
+        Ok(id) => Some((id, firmware)),
+        Err(_) => error!(
+            "Invalid LEDGER_TARGET_ID value '{}', must be a number such as 0x33100004.",
+            target_id
+        ),
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct FirmwareInfo {
-    pub perso: String,
+// List the apps available in Ledger's catalogue. Set LEDGER_SEARCH to only list the apps whose name
+// contains this text and LEDGER_CATEGORY to only list the apps of this kind (such as "currency").
+fn print_catalog(network: &dyn Network, target_id: u32, firmware: &str) {
+    let apps = match app_catalogue(network, target_id, firmware) {
+        Ok(a) => a,
+        Err(e) => error!("{}.", e),
+    };
+    let search = env::var("LEDGER_SEARCH").ok().map(|s| s.to_lowercase());
+    let category = env::var("LEDGER_CATEGORY").ok();
+    let matching: Vec<_> = apps
+        .iter()
+        .filter(|app| {
+            search
+                .as_ref()
+                .is_none_or(|s| app.name.to_lowercase().contains(s))
+        })
+        .filter(|app| {
+            category.as_ref().is_none_or(|c| {
+                app.category
+                    .as_ref()
+                    .is_some_and(|ac| ac.eq_ignore_ascii_case(c))
+            })
+        })
+        .collect();
+
+    println!(
+        "Apps available for target id {:#x} on firmware version {}:",
+        target_id, firmware
+    );
+    for app in &matching {
+        println!(
+            "  - {} {}",
+            app.name,
+            app.version.as_deref().unwrap_or("(unknown version)")
+        );
+        let size = match app.size {
+            Some(bytes) => format!("{} KB", bytes.div_ceil(1024)),
+            None => "unknown".to_string(),
+        };
+        println!(
+            "    Size: {}. Category: {}.",
+            size,
+            app.category.as_deref().unwrap_or("unknown")
+        );
+        println!("    Hash: {}.", app.hash);
+    }
+    println!("{} app(s) out of {}.", matching.len(), apps.len());
 }
 
-impl FirmwareInfo {
-    pub fn from_device(device_info: &DeviceInfo) -> Self {
-        let dev_ver_resp = minreq::Request::new(
-            minreq::Method::Post,
-            &format!("{}/get_device_version", BASE_API_V1_URL),
-        )
-        .with_param("livecommonversion", LIVE_COMMON_VERSION)
-        .with_json(&serde_json::json!({
-        "provider": PROVIDER,
-        "target_id": device_info.target_id,
-        }))
-        .unwrap()
-        .send()
-        .unwrap();
-        let device_version = dev_ver_resp.json::<DeviceVersion>().unwrap();
-
-        let firm_resp = minreq::Request::new(
-            minreq::Method::Post,
-            &format!("{}/get_firmware_version", BASE_API_V1_URL),
-        )
-        .with_param("livecommonversion", LIVE_COMMON_VERSION)
-        .with_json(&serde_json::json!({
-        "provider": PROVIDER,
-        "device_version": device_version.id,
-        "version_name": &device_info.version,
-        }))
-        .unwrap()
-        .send()
-        .unwrap();
-        firm_resp.json::<FirmwareInfo>().unwrap()
+// The version of the app to install, if pinned through the LEDGER_APP_VERSION or LEDGER_APP_HASH env
+// var.
+fn app_pin() -> Option<AppPin> {
+    match (env::var("LEDGER_APP_VERSION"), env::var("LEDGER_APP_HASH")) {
+        (Ok(_), Ok(_)) => error!("Only one of LEDGER_APP_VERSION and LEDGER_APP_HASH can be set."),
+        (Ok(version), Err(_)) => Some(AppPin::Version(version)),
+        (Err(_), Ok(hash)) => Some(AppPin::Hash(hash)),
+        (Err(_), Err(_)) => None,
     }
 }
 
-fn genuine_check(ledger_api: &TransportNativeHID) {
-    let device_info = device_info(ledger_api);
-    let firmware_info = FirmwareInfo::from_device(&device_info);
-
-    println!("Querying Ledger's remote HSM to perform the genuine check. You might have to confirm the operation on your device.");
-    let genuine_ws_url = UrlSerializer::new(format!("{}/genuine?", BASE_SOCKET_URL))
-        .append_pair("targetId", &device_info.target_id.to_string())
-        .append_pair("perso", &firmware_info.perso)
-        .finish();
-    if let Err(e) = query_via_websocket(&ledger_api, &genuine_ws_url) {
-        error!("Error when performing genuine check: {}.", e);
+fn install(ledger_api: &impl Transport, network: &dyn Network, is_testnet: bool) {
+    let res = match app_pin() {
+        Some(pin) => install_app_version(ledger_api, network, is_testnet, &pin, &mut print_event),
+        None => install_app(ledger_api, network, is_testnet, &mut print_event),
+    };
+    if let Err(e) = res {
+        error!("{}.", e);
     }
-    println!("Success. Your Ledger is genuine.");
 }
 
-// DON'T DELETE ME JUST YET.
-// This appears to be the "old" (api v1) way of querying information about the Bitcoin app for a
-// device. It does give access to more data, so i'm keeping it around for now just in case.
-//
-// See
-// https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/manager/index.ts#L103-L104.
-//
-// See above for firmware info.
-//
-//let compatible_apps = minreq::Request::new(
-//minreq::Method::Post,
-//"https://manager.api.live.ledger.com/api/get_apps",
-//)
-//.with_param("livecommonversion", "34.0.0")
-//.with_json(&serde_json::json!({
-//"provider": PROVIDER,
-//"current_se_firmware_final_version": firmware_id,
-//"device_version": device_id,
-//}))
-//.unwrap()
-//.with_param("firmware_version_name", device_info.version)
-//.send()
-//.unwrap();
-//let bitcoin_apps: Vec<_> = compatible_apps
-//.json::<serde_json::Value>()
-//.unwrap()
-//.get("application_versions")
-//.unwrap()
-//.as_array()
-//.unwrap()
-//.into_iter()
-//.filter(|o| {
-//o.as_object()
-//.unwrap()
-//.get("name")
-//.unwrap()
-//.as_str()
-//.unwrap()
-//.to_lowercase()
-//== "bitcoin test"
-////.contains("bitcoin")
-//})
-////.inspect(|o| println!("{}", serde_json::to_string_pretty(&o).unwrap()))
-//.cloned()
-//.collect();
-//let bitcoin_app = &bitcoin_apps[0];
-//println!("{}", bitcoin_app);
-
-#[derive(Debug, Clone, Deserialize)]
-struct BitcoinAppV2 {
-    #[serde(rename = "versionName")]
-    version_name: String,
-    perso: String,
-    #[serde(rename = "deleteKey")]
-    delete_key: String,
-    firmware: String,
-    #[serde(rename = "firmwareKey")]
-    firmware_key: String,
-    hash: String,
+fn print_app_versions(ledger_api: &impl Transport, network: &dyn Network, is_testnet: bool) {
+    let versions = match app_versions(ledger_api, network, is_testnet) {
+        Ok(v) => v,
+        Err(e) => error!("{}.", e),
+    };
+    println!("Versions of the app available for your device:");
+    for app in &versions {
+        println!(
+            "  - {} (hash: {})",
+            app.version.as_deref().unwrap_or("unknown version"),
+            app.hash
+        );
+    }
 }
 
-// Install the Bitcoin app on the device.
-fn install_app(ledger_api: &TransportNativeHID, is_testnet: bool) {
-    // First of all make sure it's not already installed.
-    let lowercase_app_name = if is_testnet {
-        "bitcoin test"
-    } else {
-        "bitcoin"
+fn print_firmware_status(ledger_api: &impl Transport, network: &dyn Network) {
+    let status = match firmware_status(ledger_api, network) {
+        Ok(s) => s,
+        Err(e) => error!("{}.", e),
     };
-    let apps = match list_installed_apps(&ledger_api) {
-        Ok(a) => a,
-        Err(e) => error!("Error listing installed applications: {}.", e),
+    println!("Target id: {:#x}.", status.target_id);
+    println!("SE version: {}.", status.se_version);
+    println!(
+        "MCU version: {}.",
+        status.mcu_version.as_deref().unwrap_or("unknown")
+    );
+    let latest = match status.latest {
+        Some(l) => l,
+        None => return println!("Your device is running the latest firmware."),
     };
-    if apps
-        .iter()
-        .any(|app| app.name.to_lowercase() == lowercase_app_name)
-    {
-        //error!("Bitcoin app already installed. Use the update command to update it.");
+    println!("Latest firmware: {}.", latest.version);
+    if let Some(notes) = &latest.release_notes {
+        println!("Release notes:\n{}", notes);
     }
+    if latest.needs_mcu_update {
+        println!(
+            "The upgrade requires an MCU update (compatible MCU versions: {}).",
+            latest.mcu_versions.join(", ")
+        );
+    } else {
+        println!("The upgrade doesn't require an MCU update.");
+    }
+}
 
-    // Get the Bitcoin app information for this device.
-    // This uses the v2 API. See for reference:
-    // - https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/apps/listApps/v2.ts
-    // - https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/device-core/src/managerApi/repositories/HttpManagerApiRepository.ts#L211
-    // There is also another way which seems to be the API v1 way of getting the app info. See
-    // above the commented out code.
-    let device_info = device_info(ledger_api);
-    let resp_apps = minreq::Request::new(
-        minreq::Method::Get,
-        format!("{}/apps/by-target", BASE_API_V2_URL),
-    )
-    .with_param("livecommonversion", "34.0.0")
-    .with_param("provider", PROVIDER.to_string()) // TODO: allow to configure the provider
-    .with_param("target_id", device_info.target_id.to_string())
-    .with_param("firmware_version_name", device_info.version.clone())
-    .send();
-    let resp_apps = match resp_apps {
-        Ok(r) => r,
-        Err(e) => error!(
-            "Error when querying information about the Bitcoin app for this device: {}.",
-            e
-        ),
-    };
-    let bitcoin_app = resp_apps
-        .json::<Vec<BitcoinAppV2>>()
-        // FIXME: is versionName guaranteed to be the name? What's "version" for?
-        .map(|apps| {
-            apps.into_iter()
-                .find(|o| o.version_name.to_lowercase() == lowercase_app_name)
-        });
-    let bitcoin_app = match bitcoin_app {
-        Ok(Some(a)) => a,
-        Ok(None) => {
-            error!("Could not get info about Bitcoin app.",);
+fn run(command: Command, ledger_api: &impl Transport, network: &dyn Network) {
+    match command {
+        Command::GetInfo => {
+            print_ledger_info(ledger_api);
         }
-        Err(e) => {
-            error!(
-                "Error when deserializing response into list of Bitcoin apps info: {}",
-                e
-            );
+        Command::GenuineCheck => {
+            let report = match genuine_check(ledger_api, network, &mut print_event) {
+                Ok(r) => r,
+                Err(e) => error!("{}.", e),
+            };
+            println!("Genuine check report: {:#?}", report);
+            if let Ok(path) = env::var("LEDGER_GENUINE_REPORT") {
+                let json = match serde_json::to_string_pretty(&report) {
+                    Ok(j) => j,
+                    Err(e) => error!("Error serializing the genuine check report: {}.", e),
+                };
+                if let Err(e) = fs::write(&path, json) {
+                    error!(
+                        "Error writing the genuine check report to '{}': {}.",
+                        path, e
+                    );
+                }
+                println!("Genuine check report written to '{}'.", path);
+            }
+        }
+        Command::Audit => {
+            let audits = match audit(ledger_api, network, &mut print_event) {
+                Ok(a) => a,
+                Err(e) => error!("{}.", e),
+            };
+            for app in &audits {
+                println!("  - {}: {}.", app.name, app.status);
+            }
+            let anomalies = audits.iter().filter(|a| a.status.is_anomaly()).count();
+            if anomalies > 0 {
+                error!("Found {} app(s) which need your attention.", anomalies);
+            }
+        }
+        Command::Catalog => {
+            let (target_id, firmware) = catalog_target().unwrap_or_else(|| {
+                let device_info = device_info(ledger_api);
+                (device_info.target_id, device_info.version)
+            });
+            print_catalog(network, target_id, &firmware);
+        }
+        Command::FirmwareStatus => print_firmware_status(ledger_api, network),
+        Command::MainAppVersions => print_app_versions(ledger_api, network, false),
+        Command::TestAppVersions => print_app_versions(ledger_api, network, true),
+        Command::InstallMainApp => install(ledger_api, network, false),
+        Command::InstallTestApp => install(ledger_api, network, true),
+        Command::UpdateMainApp => {
+            if let Err(e) = update_app(ledger_api, network, false, &mut print_event) {
+                error!("{}.", e);
+            }
+        }
+        Command::UpdateTestApp => {
+            if let Err(e) = update_app(ledger_api, network, true, &mut print_event) {
+                error!("{}.", e);
+            }
+        }
+        Command::UninstallMainApp => {
+            if let Err(e) = uninstall_app(ledger_api, network, false, &mut print_event) {
+                error!("{}.", e);
+            }
+        }
+        Command::UninstallTestApp => {
+            if let Err(e) = uninstall_app(ledger_api, network, true, &mut print_event) {
+                error!("{}.", e);
+            }
+        }
+        Command::OpenMainApp => {
+            print_event(Event::WaitingForConfirmation(
+                "Opening app on your Ledger".to_string(),
+            ));
+            if let Err(e) = open_app(ledger_api, b"Bitcoin") {
+                error!("{}.", e);
+            }
+        }
+        Command::OpenTestApp => {
+            print_event(Event::WaitingForConfirmation(
+                "Opening app on your Ledger".to_string(),
+            ));
+            if let Err(e) = open_app(ledger_api, b"Bitcoin Test") {
+                error!("{}.", e);
+            }
+        }
+        Command::Open(name) => {
+            if let Err(e) = open_installed_app(ledger_api, &name, &mut print_event) {
+                error!("{}.", e);
+            }
         }
+        Command::QuitApp => {
+            if let Err(e) = quit_app(ledger_api) {
+                error!("{}.", e);
+            }
+            println!("Back to the dashboard.");
+        }
+        Command::UpdateMcu => {
+            if let Err(e) = update_mcu(ledger_api, network, &mut print_event) {
+                error!("{}.", e);
+            }
+        }
+        Command::UpdateeFirmware => {
+            unimplemented!()
+        }
+    }
+}
+
+// Replay a recorded session against a mock device and mock servers.
+fn replay_session(path: &str) {
+    let replayer = match Replayer::load(path) {
+        Ok(r) => r,
+        Err(e) => error!("Error loading session file '{}': {}.", path, e),
     };
+    // The command is recorded at the start of the session, but can be overriden. The start event is
+    // consumed either way so the replay begins with the first actual interaction.
+    let start = replayer.start();
+    let command = Command::get().or_else(|| {
+        let (cmd_str, is_testnet) = start?;
+        Command::parse(&cmd_str, is_testnet)
+    });
+    let command = if let Some(cmd) = command {
+        cmd
+    } else {
+        error!("No valid command recorded in the session file and none passed through the LEDGER_COMMAND env var.");
+    };
+
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    run(command, &ledger_api, &network);
 
-    // Now install the app by connecting through their websocket thing to their HSM. Make sure to
-    // properly escape the parameters in the request's parameter.
-    let install_ws_url = UrlSerializer::new(format!("{}/install?", BASE_SOCKET_URL))
-        .append_pair("targetId", &device_info.target_id.to_string())
-        .append_pair("perso", &bitcoin_app.perso)
-        .append_pair("deleteKey", &bitcoin_app.delete_key)
-        .append_pair("firmware", &bitcoin_app.firmware)
-        .append_pair("firmwareKey", &bitcoin_app.firmware_key)
-        .append_pair("hash", &bitcoin_app.hash)
-        .finish();
-    println!("Querying Ledger's remote HSM to install the app. You might have to confirm the operation on your device.");
-    if let Err(e) = query_via_websocket(&ledger_api, &install_ws_url) {
+    if replayer.remaining() > 0 {
         error!(
-            "Got an error when installing Bitcoin app from Ledger's remote HSM: {}.",
-            e
+            "Session diverged from the recording: {} recorded events were not replayed.",
+            replayer.remaining()
         );
     }
-    println!("Successfully installed the app.");
+    println!("Session replayed successfully.");
 }
 
-fn open_app(ledger_api: &TransportNativeHID, name: &[u8]) {
-    let mut command = OPEN_APP_COMMAND_TEMPLATE;
-    command.data = name;
+// Where to cache the Manager API responses, if anywhere. Can be set through the LEDGER_CACHE_DIR
+// env var.
+fn cache_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("LEDGER_CACHE_DIR") {
+        return Some(dir.into());
+    }
+    let cache_home = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok()?;
+    Some(cache_home.join("ledger_installer"))
+}
 
-    println!("Opening app on your Ledger. You might have to confirm on your device.",);
-    let resp = match ledger_api.exchange(&command) {
-        Ok(r) => r,
-        Err(e) => error!("Error opening app: {}.", e),
+// Access to Ledger's servers for this command, with the Manager API responses cached on disk for
+// LEDGER_CACHE_TTL seconds if the command can use them. Set LEDGER_OFFLINE to only use the cached
+// responses.
+fn with_network(command: &Command, f: impl FnOnce(&dyn Network)) {
+    match cache(command) {
+        Some(cache) => {
+            f(&cache);
+            if let Some(e) = cache.error() {
+                eprintln!("Warning: {}.", e);
+            }
+        }
+        None => f(&live_network()),
+    }
+}
+
+// The cache of the Manager API responses, for the commands which can use it.
+fn cache(command: &Command) -> Option<CachingNetwork<LiveNetwork>> {
+    let offline = env::var("LEDGER_OFFLINE").is_ok();
+    if !command.can_use_cache() {
+        if offline {
+            error!("This command needs access to Ledger's servers, it can't be run offline.");
+        }
+        return None;
+    }
+    let dir = match cache_dir() {
+        Some(d) => d,
+        None if offline => error!("Offline mode needs a cache directory, set LEDGER_CACHE_DIR."),
+        None => return None,
     };
-    if resp.retcode() != StatusCode::OK as u16 {
-        error!("Error opening app. Ledger response: {:#x?}.", resp);
+    let ttl = match env::var("LEDGER_CACHE_TTL") {
+        Ok(ttl) => match ttl.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => error!(
+                "Invalid LEDGER_CACHE_TTL value '{}', must be a number of seconds.",
+                ttl
+            ),
+        },
+        Err(_) => DEFAULT_CACHE_TTL,
+    };
+    Some(CachingNetwork::new(live_network(), dir, ttl, offline))
+}
+
+// Access to Ledger's servers. An overall timeout in seconds for the session with the HSM may be set
+// through the LEDGER_TIMEOUT env var. It counts from the start of the session, not of the command.
+fn live_network() -> LiveNetwork {
+    let mut network = LiveNetwork::default();
+    if let Ok(timeout) = env::var("LEDGER_TIMEOUT") {
+        match timeout.parse() {
+            Ok(secs) => network.session_timeout = Some(Duration::from_secs(secs)),
+            Err(_) => error!(
+                "Invalid LEDGER_TIMEOUT value '{}', must be a number of seconds.",
+                timeout
+            ),
+        }
     }
+    network
 }
 
 fn main() {
+    if let Ok(path) = env::var("LEDGER_REPLAY") {
+        return replay_session(&path);
+    }
+
     let command = if let Some(cmd) = Command::get() {
         cmd
     } else {
         error!("Invalid or no command specified. The command must be passed through the LEDGER_COMMAND env var. Set LEDGER_TESTNET to use the Bitcoin testnet app instead where applicable.");
     };
 
+    // The catalogue of apps can be browsed without a device.
+    if let (Command::Catalog, Some((target_id, firmware))) = (&command, catalog_target()) {
+        return with_network(&command, |network| {
+            print_catalog(network, target_id, &firmware)
+        });
+    }
+
     let ledger_api = ledger_api();
-    match command {
-        Command::GetInfo => {
-            print_ledger_info(&ledger_api);
-        }
-        Command::GenuineCheck => {
-            genuine_check(&ledger_api);
-        }
-        Command::InstallMainApp => {
-            install_app(&ledger_api, false);
-        }
-        Command::InstallTestApp => {
-            install_app(&ledger_api, true);
-        }
-        Command::OpenMainApp => {
-            open_app(&ledger_api, b"Bitcoin");
+    if let Ok(path) = env::var("LEDGER_RECORD") {
+        let recorder = match Recorder::create(&path) {
+            Ok(r) => r,
+            Err(e) => error!("Error creating session file '{}': {}.", path, e),
+        };
+        let start = SessionEvent::Start {
+            command: env::var("LEDGER_COMMAND").unwrap_or_default(),
+            testnet: env::var("LEDGER_TESTNET").is_ok(),
+        };
+        if let Err(e) = recorder.record(&start) {
+            error!("Error writing to session file '{}': {}.", path, e);
         }
-        Command::OpenTestApp => {
-            open_app(&ledger_api, b"Bitcoin Test");
-        }
-        Command::UpdateMainApp | Command::UpdateTestApp | Command::UpdateeFirmware => {
-            unimplemented!()
+        let ledger_api = RecordingTransport::new(ledger_api, recorder.clone());
+        with_network(&command.clone(), |network| {
+            let network = RecordingNetwork::new(network, recorder.clone());
+            run(command, &ledger_api, &network);
+        });
+        if let Some(e) = recorder.error() {
+            error!("Error writing to session file '{}': {}.", path, e);
         }
+    } else {
+        with_network(&command.clone(), |network| {
+            run(command, &ledger_api, network)
+        });
     }
 }
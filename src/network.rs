@@ -0,0 +1,313 @@
+use std::{
+    error, fmt, io,
+    net::{self, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// How often to check for cancellation and timeouts while waiting for a message from the HSM.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// How long to wait for the HSM to acknowledge the close of the connection once the session is over.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A websocket session with the HSM was interrupted before it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    /// The connection could not be established in time, the HSM didn't send a message in time or
+    /// the session lasted longer than its timeout.
+    Timeout,
+    /// The operation was cancelled through its [`CancelHandle`].
+    Cancelled,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "The session with Ledger's remote HSM timed out"),
+            Self::Cancelled => write!(f, "The session with Ledger's remote HSM was cancelled"),
+        }
+    }
+}
+
+impl error::Error for SessionError {}
+
+/// Can be used to cancel an ongoing operation from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the cancellation of the operation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An open websocket connection to Ledger's remote HSM.
+pub trait HsmSocket {
+    fn read(&mut self) -> Result<tungstenite::Message, Box<dyn error::Error>>;
+    fn send(&mut self, msg: tungstenite::Message) -> Result<(), Box<dyn error::Error>>;
+
+    /// Check whether the session should be interrupted, for instance because it was cancelled.
+    /// This is called before each exchange with the device the HSM asks for. An exchange in flight
+    /// isn't interrupted, including one waiting for the user to confirm on the device.
+    fn check_interrupted(&mut self) -> Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    /// Start closing the connection once the session is over. The HSM acknowledges it with a Close
+    /// frame of its own, which further reads only wait for briefly.
+    fn close(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.send(tungstenite::Message::Close(None))
+    }
+}
+
+/// Access to Ledger's servers: the Manager API over HTTP and the HSM over websockets.
+///
+/// Urls are passed with their parameters already (and properly escaped) in the query string.
+pub trait Network {
+    /// Perform a GET request, returning the body of the response.
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>>;
+
+    /// Perform a POST request with the given JSON body, returning the body of the response.
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>>;
+
+    /// Open a websocket connection.
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>>;
+}
+
+impl<N: Network + ?Sized> Network for &N {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        (**self).get(url)
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        (**self).post_json(url, body)
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        (**self).connect(url)
+    }
+}
+
+impl<N: Network + ?Sized> Network for Box<N> {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        (**self).get(url)
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        (**self).post_json(url, body)
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        (**self).connect(url)
+    }
+}
+
+// The body of a response, erroring if the server didn't answer with a success, for instance with an
+// error page.
+fn response_body(url: &str, resp: &minreq::Response) -> Result<String, Box<dyn error::Error>> {
+    if !(200..300).contains(&resp.status_code) {
+        return Err(format!(
+            "Request to '{}' failed with status {} {}",
+            url, resp.status_code, resp.reason_phrase
+        )
+        .into());
+    }
+    Ok(resp.as_str()?.to_string())
+}
+
+/// Talk to Ledger's actual servers.
+#[derive(Debug, Clone)]
+pub struct LiveNetwork {
+    /// Maximum time to establish a connection.
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for a response to an HTTP request or a message from the HSM.
+    pub read_timeout: Duration,
+    /// Websocket sessions are interrupted with a [`SessionError::Timeout`] once they lasted this
+    /// long, counting from the connection to the HSM.
+    pub session_timeout: Option<Duration>,
+    /// Websocket sessions are interrupted with a [`SessionError::Cancelled`] once cancelled.
+    pub cancel: CancelHandle,
+}
+
+impl Default for LiveNetwork {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            session_timeout: None,
+            cancel: CancelHandle::new(),
+        }
+    }
+}
+
+impl LiveNetwork {
+    // Open a TCP connection to the host of this url, within the connect timeout.
+    fn connect_tcp(&self, url: &str) -> Result<net::TcpStream, Box<dyn error::Error>> {
+        let uri: tungstenite::http::Uri = url.parse()?;
+        let host = uri.host().ok_or("No host in websocket url")?;
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("ws") {
+            80
+        } else {
+            443
+        });
+
+        let mut last_err = None;
+        for addr in (host, port).to_socket_addrs()? {
+            match net::TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(match last_err {
+            Some(e) if e.kind() == io::ErrorKind::TimedOut => SessionError::Timeout.into(),
+            Some(e) => e.into(),
+            None => format!("Could not resolve '{}'", host).into(),
+        })
+    }
+}
+
+impl Network for LiveNetwork {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        let resp = minreq::get(url)
+            .with_timeout(self.read_timeout.as_secs())
+            .send()?;
+        response_body(url, &resp)
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        let resp = minreq::post(url)
+            .with_timeout(self.read_timeout.as_secs())
+            .with_json(body)?
+            .send()?;
+        response_body(url, &resp)
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        let deadline = self.session_timeout.map(|t| Instant::now() + t);
+        let stream = self.connect_tcp(url)?;
+        stream.set_read_timeout(Some(self.connect_timeout))?;
+        stream.set_write_timeout(Some(self.connect_timeout))?;
+        // Keep a handle to the underlying socket to change the timeouts after the handshake.
+        let tcp_handle = stream.try_clone()?;
+        let (socket, _) = tungstenite::client_tls(url, stream).map_err(handshake_error)?;
+
+        // Don't block on reads for too long so we can regularly check whether the session should
+        // be interrupted.
+        tcp_handle.set_read_timeout(Some(POLL_INTERVAL))?;
+        tcp_handle.set_write_timeout(Some(self.read_timeout))?;
+        Ok(Box::new(LiveSocket {
+            socket,
+            read_timeout: self.read_timeout,
+            deadline,
+            cancel: self.cancel.clone(),
+        }))
+    }
+}
+
+// A handshake which stalls past the connect timeout either gets interrupted or fails with a timeout,
+// depending on the platform. Both are reported as a timeout.
+fn handshake_error<Role: tungstenite::handshake::HandshakeRole>(
+    e: tungstenite::HandshakeError<Role>,
+) -> Box<dyn error::Error> {
+    match e {
+        tungstenite::HandshakeError::Interrupted(_) => SessionError::Timeout.into(),
+        tungstenite::HandshakeError::Failure(tungstenite::Error::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            SessionError::Timeout.into()
+        }
+        tungstenite::HandshakeError::Failure(e) => e.to_string().into(),
+    }
+}
+
+struct LiveSocket {
+    socket: tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<net::TcpStream>>,
+    read_timeout: Duration,
+    deadline: Option<Instant>,
+    cancel: CancelHandle,
+}
+
+impl LiveSocket {
+    // Close the connection cleanly and return the reason for interrupting the session.
+    fn interrupt(&mut self, reason: SessionError) -> Box<dyn error::Error> {
+        let _ = self.socket.close(None);
+        let _ = self.socket.flush();
+        reason.into()
+    }
+}
+
+impl HsmSocket for LiveSocket {
+    fn read(&mut self) -> Result<tungstenite::Message, Box<dyn error::Error>> {
+        let start = Instant::now();
+        loop {
+            self.check_interrupted()?;
+            match self.socket.read() {
+                Ok(msg) => return Ok(msg),
+                Err(tungstenite::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if start.elapsed() > self.read_timeout {
+                        return Err(self.interrupt(SessionError::Timeout));
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn send(&mut self, msg: tungstenite::Message) -> Result<(), Box<dyn error::Error>> {
+        Ok(self.socket.send(msg)?)
+    }
+
+    fn check_interrupted(&mut self) -> Result<(), Box<dyn error::Error>> {
+        if self.cancel.is_cancelled() {
+            return Err(self.interrupt(SessionError::Cancelled));
+        }
+        if self.deadline.is_some_and(|d| Instant::now() > d) {
+            return Err(self.interrupt(SessionError::Timeout));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.socket.close(None)?;
+        // The session is already over, don't wait long for the HSM to acknowledge the close.
+        self.read_timeout = CLOSE_TIMEOUT;
+        Ok(())
+    }
+}
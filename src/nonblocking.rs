@@ -0,0 +1,671 @@
+//! An async variant of the operations, for use with tokio.
+//!
+//! Requests to the Manager API and the websocket sessions with the HSM are performed
+//! asynchronously. Exchanges with the device are run on tokio's blocking thread pool. Dropping the
+//! future of an operation cancels it: the websocket is closed and no more commands are sent to the
+//! device (an exchange already in flight still completes).
+//!
+//! There are no connect, read or overall timeouts: wrap the calls in `tokio::time::timeout` to bound
+//! them. Only waiting for the HSM to acknowledge the close of the connection is bounded.
+//!
+//! The sessions with the HSM are driven by the same state machine as the blocking API, and the
+//! requests and responses to the Manager API are built and parsed by the same code.
+
+use crate::{
+    apps_by_target_url, apps_v1_request, audit_apps, check_bootloader_mode, check_device_ready,
+    check_free_space, device_version_request, find_bitcoin_app, find_pinned_app,
+    firmware_version_request, genuine_check_url, has_bitcoin_app, install_url, lowercase_app_name,
+    mcu_update_event, mcu_update_url, needs_version_history, next_mcu_request, parse_app_catalogue,
+    parse_app_versions, parse_next_mcu, plan_mcu_update, report, transport::Transport, AppAudit,
+    AppPin, BitcoinAppV2, CatalogueApp, DeviceInfo, DeviceVersion, Event, FirmwareInfo,
+    GenuineCheckReport, HsmSession, HsmStep, InstalledApp, McuUpdate, CONTINUE_LIST_APPS_COMMAND,
+    GET_VERSION_COMMAND, LIST_APPS_COMMAND,
+};
+use futures_util::{SinkExt, StreamExt};
+use ledger_apdu::{APDUAnswer, APDUCommand};
+
+use std::{error, future::Future, sync::Arc, time::Duration};
+
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
+
+// How long to wait for the HSM to acknowledge the close of the connection once the session is over.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A means of exchanging APDUs with a Ledger device asynchronously.
+pub trait AsyncTransport {
+    fn exchange(
+        &self,
+        command: APDUCommand<Vec<u8>>,
+    ) -> impl Future<Output = Result<APDUAnswer<Vec<u8>>, BoxError>> + Send;
+
+    /// See [`Transport::set_hsm_session`].
+    fn set_hsm_session(&self, _active: bool) {}
+}
+
+/// Run exchanges with a blocking [`Transport`] (such as the HID transport) on tokio's blocking
+/// thread pool.
+#[derive(Debug)]
+pub struct BlockingTransport<T> {
+    inner: Arc<T>,
+}
+
+impl<T> BlockingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+}
+
+impl<T: Transport + Send + Sync + 'static> AsyncTransport for BlockingTransport<T> {
+    fn exchange(
+        &self,
+        command: APDUCommand<Vec<u8>>,
+    ) -> impl Future<Output = Result<APDUAnswer<Vec<u8>>, BoxError>> + Send {
+        let inner = self.inner.clone();
+        async move {
+            let answer = tokio::task::spawn_blocking(move || {
+                inner.exchange(&command).map_err(|e| e.to_string())
+            })
+            .await??;
+            Ok(answer)
+        }
+    }
+
+    fn set_hsm_session(&self, active: bool) {
+        self.inner.set_hsm_session(active)
+    }
+}
+
+// Get an owned copy of one of our APDU command constants.
+fn owned_command(command: &APDUCommand<&[u8]>) -> APDUCommand<Vec<u8>> {
+    APDUCommand {
+        cla: command.cla,
+        ins: command.ins,
+        p1: command.p1,
+        p2: command.p2,
+        data: command.data.to_vec(),
+    }
+}
+
+/// An open websocket connection to Ledger's remote HSM. See [`crate::network::HsmSocket`].
+pub trait AsyncHsmSocket: Send {
+    fn read(&mut self) -> impl Future<Output = Result<tungstenite::Message, BoxError>> + Send;
+    fn send(
+        &mut self,
+        msg: tungstenite::Message,
+    ) -> impl Future<Output = Result<(), BoxError>> + Send;
+
+    /// Start closing the connection once the session is over. The HSM acknowledges it with a Close
+    /// frame of its own, which further reads only wait for briefly.
+    fn close(&mut self) -> impl Future<Output = Result<(), BoxError>> + Send;
+}
+
+/// Access to Ledger's servers: the Manager API over HTTP and the HSM over websockets. See
+/// [`crate::network::Network`].
+pub trait AsyncNetwork {
+    type Socket: AsyncHsmSocket;
+
+    /// Perform a GET request, returning the body of the response.
+    fn get(&self, url: &str) -> impl Future<Output = Result<String, BoxError>> + Send;
+
+    /// Perform a POST request with the given JSON body, returning the body of the response.
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> impl Future<Output = Result<String, BoxError>> + Send;
+
+    /// Open a websocket connection.
+    fn connect(&self, url: &str) -> impl Future<Output = Result<Self::Socket, BoxError>> + Send;
+}
+
+/// Talk to Ledger's actual servers. Unlike [`crate::network::LiveNetwork`] it doesn't time out, see
+/// the module documentation.
+#[derive(Debug, Clone, Default)]
+pub struct AsyncLiveNetwork {
+    client: reqwest::Client,
+}
+
+impl AsyncLiveNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AsyncNetwork for AsyncLiveNetwork {
+    type Socket = LiveSocket;
+
+    async fn get(&self, url: &str) -> Result<String, BoxError> {
+        Ok(self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    async fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<String, BoxError> {
+        Ok(self
+            .client
+            .post(url)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    async fn connect(&self, url: &str) -> Result<LiveSocket, BoxError> {
+        let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(LiveSocket {
+            socket,
+            closing: false,
+        })
+    }
+}
+
+/// A websocket connection to Ledger's actual HSM.
+pub struct LiveSocket {
+    socket: tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+    // Whether we started closing the connection, in which case reads don't wait long.
+    closing: bool,
+}
+
+impl AsyncHsmSocket for LiveSocket {
+    async fn read(&mut self) -> Result<tungstenite::Message, BoxError> {
+        let msg = if self.closing {
+            tokio::time::timeout(CLOSE_TIMEOUT, self.socket.next())
+                .await
+                .map_err(|_| "The HSM didn't acknowledge the close of the connection.")?
+        } else {
+            self.socket.next().await
+        };
+        Ok(msg.ok_or("The websocket was closed unexpectedly.")??)
+    }
+
+    async fn send(&mut self, msg: tungstenite::Message) -> Result<(), BoxError> {
+        Ok(self.socket.send(msg).await?)
+    }
+
+    async fn close(&mut self) -> Result<(), BoxError> {
+        self.socket.close(None).await?;
+        self.closing = true;
+        Ok(())
+    }
+}
+
+/// Query information about this device.
+pub async fn device_info(ledger_api: &impl AsyncTransport) -> Result<DeviceInfo, BoxError> {
+    let res = match ledger_api
+        .exchange(owned_command(&GET_VERSION_COMMAND))
+        .await
+    {
+        Ok(answer) => DeviceInfo::from_answer(&answer).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    res.map_err(|e| format!("Error fetching device info: {}. Is the Ledger unlocked?", e).into())
+}
+
+// Get device info, erroring if the device isn't ready to be used.
+async fn ready_device_info(ledger_api: &impl AsyncTransport) -> Result<DeviceInfo, BoxError> {
+    let device_info = device_info(ledger_api).await?;
+    check_device_ready(&device_info)?;
+    Ok(device_info)
+}
+
+/// List the applications installed on the device. The user might have to confirm on the device.
+pub async fn list_installed_apps(
+    ledger_api: &impl AsyncTransport,
+) -> Result<Vec<InstalledApp>, BoxError> {
+    let mut answer = ledger_api
+        .exchange(owned_command(&LIST_APPS_COMMAND))
+        .await?;
+    let mut installed_apps = Vec::new();
+    while !answer.data().is_empty() {
+        installed_apps
+            .extend(InstalledApp::from_response(answer.data()).map_err(|e| e.to_string())?);
+        answer = ledger_api
+            .exchange(owned_command(&CONTINUE_LIST_APPS_COMMAND))
+            .await?;
+    }
+
+    Ok(installed_apps)
+}
+
+impl FirmwareInfo {
+    /// Query the firmware information for this device from the Manager API.
+    pub async fn from_device_async(
+        network: &impl AsyncNetwork,
+        device_info: &DeviceInfo,
+    ) -> Result<Self, BoxError> {
+        Ok(query_firmware_info(network, device_info).await?.1)
+    }
+}
+
+// Query the device version of this device, then the info about its firmware.
+async fn query_firmware_info(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+) -> Result<(DeviceVersion, FirmwareInfo), BoxError> {
+    let (dev_ver_url, dev_ver_body) = device_version_request(device_info);
+    let dev_ver_resp = network.post_json(&dev_ver_url, &dev_ver_body).await?;
+    let device_version: DeviceVersion = serde_json::from_str(&dev_ver_resp)?;
+
+    let (firm_url, firm_body) = firmware_version_request(device_info, &device_version);
+    let firm_resp = network.post_json(&firm_url, &firm_body).await?;
+    Ok((device_version, serde_json::from_str(&firm_resp)?))
+}
+
+/// Async version of [`crate::query_via_websocket`].
+pub async fn query_via_websocket(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    url: &str,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<Option<serde_json::Value>, BoxError> {
+    // The end of the session must also be signaled when the future is dropped.
+    let _session = HsmSessionGuard::new(ledger_api);
+    hsm_session(ledger_api, network, url, on_event).await
+}
+
+// See the blocking version.
+struct HsmSessionGuard<'a, T: AsyncTransport>(&'a T);
+
+impl<'a, T: AsyncTransport> HsmSessionGuard<'a, T> {
+    fn new(ledger_api: &'a T) -> Self {
+        ledger_api.set_hsm_session(true);
+        Self(ledger_api)
+    }
+}
+
+impl<T: AsyncTransport> Drop for HsmSessionGuard<'_, T> {
+    fn drop(&mut self) {
+        self.0.set_hsm_session(false);
+    }
+}
+
+async fn hsm_session(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    url: &str,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<Option<serde_json::Value>, BoxError> {
+    let mut socket = network.connect(url).await?;
+    let mut session = HsmSession::default();
+    let mut step = HsmStep::Read;
+
+    loop {
+        step = match step {
+            HsmStep::Read => session.on_message(socket.read().await?, on_event)?,
+            HsmStep::Exchange(command) => {
+                let answer = ledger_api
+                    .exchange(command)
+                    .await
+                    .map_err(|e| e.to_string());
+                session.on_answer(answer, on_event)?
+            }
+            HsmStep::Send(msg) => {
+                socket.send(msg).await?;
+                HsmStep::Read
+            }
+            HsmStep::Fail(msg, e) => {
+                socket.send(msg).await?;
+                return Err(e.into());
+            }
+            HsmStep::Done(result) => {
+                close_socket(&mut socket).await;
+                return Ok(result);
+            }
+        }
+    }
+}
+
+// See the blocking version.
+async fn close_socket(socket: &mut impl AsyncHsmSocket) {
+    if socket.close().await.is_ok() {
+        while let Ok(msg) = socket.read().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    }
+}
+
+/// Check the device is genuine through Ledger's remote HSM.
+pub async fn genuine_check(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<GenuineCheckReport, BoxError> {
+    let res = genuine_check_inner(ledger_api, network, on_event).await;
+    report(res, "Success. Your Ledger is genuine.", on_event)
+}
+
+async fn genuine_check_inner(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<GenuineCheckReport, BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    let firmware_info = FirmwareInfo::from_device_async(network, &device_info)
+        .await
+        .map_err(|e| format!("Error querying firmware information for this device: {}", e))?;
+
+    on_event(Event::ConnectingToHsm(
+        "perform the genuine check".to_string(),
+    ));
+    let genuine_ws_url = genuine_check_url(&device_info, &firmware_info);
+    let result = query_via_websocket(ledger_api, network, &genuine_ws_url, on_event)
+        .await
+        .map_err(|e| format!("Error when performing genuine check: {}", e))?;
+    Ok(GenuineCheckReport::new(
+        &device_info,
+        &firmware_info,
+        result,
+    ))
+}
+
+async fn query_installed_apps(
+    ledger_api: &impl AsyncTransport,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<Vec<InstalledApp>, BoxError> {
+    on_event(Event::WaitingForConfirmation(
+        "Querying installed applications from your Ledger".to_string(),
+    ));
+    Ok(list_installed_apps(ledger_api)
+        .await
+        .map_err(|e| format!("Error listing installed applications: {}", e))?)
+}
+
+// Get the catalogue of the latest version of the apps for this device.
+async fn query_app_catalogue(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+) -> Result<Vec<BitcoinAppV2>, BoxError> {
+    let resp_apps = network
+        .get(&apps_by_target_url(
+            device_info.target_id,
+            &device_info.version,
+        ))
+        .await
+        .map_err(|e| {
+            format!(
+                "Error when querying the catalogue of apps for this device: {}",
+                e
+            )
+        })?;
+    Ok(parse_app_catalogue(&resp_apps).map_err(|e| e.to_string())?)
+}
+
+async fn bitcoin_app_info(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+    is_testnet: bool,
+) -> Result<BitcoinAppV2, BoxError> {
+    let resp_apps = network
+        .get(&apps_by_target_url(
+            device_info.target_id,
+            &device_info.version,
+        ))
+        .await
+        .map_err(|e| {
+            format!(
+                "Error when querying information about the Bitcoin app for this device: {}",
+                e
+            )
+        })?;
+    Ok(find_bitcoin_app(&resp_apps, is_testnet).map_err(|e| e.to_string())?)
+}
+
+// Get all the published versions of all the apps for this device, latest first.
+async fn query_all_app_versions(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+) -> Result<Vec<BitcoinAppV2>, BoxError> {
+    let (device_version, firmware_info) = query_firmware_info(network, device_info)
+        .await
+        .map_err(|e| format!("Error querying firmware information for this device: {}", e))?;
+    let (url, body) = apps_v1_request(device_info, &device_version, &firmware_info);
+    let resp = network
+        .post_json(&url, &body)
+        .await
+        .map_err(|e| format!("Error when querying the versions of the apps: {}", e))?;
+    Ok(parse_app_versions(&resp)?)
+}
+
+// Get all the published versions of the app with this name for this device, latest first.
+async fn query_app_versions(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+    name: &str,
+) -> Result<Vec<BitcoinAppV2>, BoxError> {
+    let mut versions = query_all_app_versions(network, device_info).await?;
+    versions.retain(|app| app.version_name.eq_ignore_ascii_case(name));
+    Ok(versions)
+}
+
+// Get the Bitcoin app information for this pinned version. See the blocking version.
+async fn pinned_bitcoin_app_info(
+    network: &impl AsyncNetwork,
+    device_info: &DeviceInfo,
+    is_testnet: bool,
+    pin: &AppPin,
+) -> Result<BitcoinAppV2, BoxError> {
+    let latest = bitcoin_app_info(network, device_info, is_testnet).await?;
+    if pin.matches(&latest) {
+        return Ok(latest);
+    }
+
+    let versions = query_app_versions(network, device_info, &latest.version_name).await?;
+    Ok(find_pinned_app(
+        &versions,
+        pin,
+        &latest.version_name,
+        device_info,
+    )?)
+}
+
+/// List all the versions of the Bitcoin app (or the Bitcoin Test app) published for the device's
+/// firmware, latest first.
+pub async fn app_versions(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+) -> Result<Vec<CatalogueApp>, BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    let versions =
+        query_app_versions(network, &device_info, lowercase_app_name(is_testnet)).await?;
+    Ok(versions.into_iter().map(CatalogueApp::from).collect())
+}
+
+/// Install the Bitcoin app (or the Bitcoin Test app) on the device.
+pub async fn install_app(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let res = install_app_inner(ledger_api, network, is_testnet, None, on_event).await;
+    report(res, "Successfully installed the app.", on_event)
+}
+
+/// Install this specific version of the Bitcoin app (or the Bitcoin Test app) on the device. Fails
+/// if this version isn't available for the device's firmware.
+pub async fn install_app_version(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    pin: &AppPin,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let res = install_app_inner(ledger_api, network, is_testnet, Some(pin), on_event).await;
+    report(
+        res,
+        &format!("Successfully installed the app with {}.", pin),
+        on_event,
+    )
+}
+
+async fn install_app_inner(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    pin: Option<&AppPin>,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    let apps = query_installed_apps(ledger_api, on_event).await?;
+    if has_bitcoin_app(&apps, is_testnet) {
+        //return Err("Bitcoin app already installed. Use the update command to update it.".into());
+    }
+
+    let bitcoin_app = match pin {
+        Some(pin) => pinned_bitcoin_app_info(network, &device_info, is_testnet, pin).await?,
+        None => bitcoin_app_info(network, &device_info, is_testnet).await?,
+    };
+    check_free_space(&device_info, &apps, &bitcoin_app)?;
+    on_event(Event::ConnectingToHsm("install the app".to_string()));
+    let install_ws_url = install_url(
+        &device_info,
+        &bitcoin_app,
+        (&bitcoin_app.firmware, &bitcoin_app.firmware_key),
+    );
+    query_via_websocket(ledger_api, network, &install_ws_url, on_event)
+        .await
+        .map_err(|e| {
+            format!(
+                "Got an error when installing Bitcoin app from Ledger's remote HSM: {}",
+                e
+            )
+        })?;
+    Ok(())
+}
+
+/// Uninstall the Bitcoin app (or the Bitcoin Test app) from the device.
+pub async fn uninstall_app(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let res = uninstall_app_inner(ledger_api, network, is_testnet, on_event).await;
+    report(res, "Successfully uninstalled the app.", on_event)
+}
+
+async fn uninstall_app_inner(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    let apps = query_installed_apps(ledger_api, on_event).await?;
+    if !has_bitcoin_app(&apps, is_testnet) {
+        return Err("Bitcoin app is not installed".into());
+    }
+
+    let bitcoin_app = bitcoin_app_info(network, &device_info, is_testnet).await?;
+    on_event(Event::ConnectingToHsm("uninstall the app".to_string()));
+    let uninstall_ws_url = install_url(
+        &device_info,
+        &bitcoin_app,
+        (&bitcoin_app.delete, &bitcoin_app.delete_key),
+    );
+    query_via_websocket(ledger_api, network, &uninstall_ws_url, on_event)
+        .await
+        .map_err(|e| {
+            format!(
+                "Got an error when uninstalling Bitcoin app from Ledger's remote HSM: {}",
+                e
+            )
+        })?;
+    Ok(())
+}
+
+/// Update the Bitcoin app (or the Bitcoin Test app) on the device to the latest version available
+/// for its firmware.
+pub async fn update_app(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    is_testnet: bool,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<(), BoxError> {
+    let mut res = uninstall_app_inner(ledger_api, network, is_testnet, on_event).await;
+    if res.is_ok() {
+        res = install_app_inner(ledger_api, network, is_testnet, None, on_event).await;
+    }
+    report(res, "Successfully updated the app.", on_event)
+}
+
+/// Check every installed app against Ledger's catalogue of apps for the device's firmware.
+pub async fn audit(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<Vec<AppAudit>, BoxError> {
+    let res = audit_inner(ledger_api, network, on_event).await;
+    report(res, "Audited the installed apps.", on_event)
+}
+
+async fn audit_inner(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<Vec<AppAudit>, BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    let apps = query_installed_apps(ledger_api, on_event).await?;
+    let catalogue = query_app_catalogue(network, &device_info).await?;
+    let history = if needs_version_history(&apps, &catalogue) {
+        query_all_app_versions(network, &device_info).await?
+    } else {
+        Vec::new()
+    };
+    Ok(audit_apps(&apps, &catalogue, &history))
+}
+
+/// Update the MCU of a device in bootloader mode. See [`crate::update_mcu`].
+pub async fn update_mcu(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<McuUpdate, BoxError> {
+    let res = update_mcu_inner(ledger_api, network, on_event).await;
+    let msg = res.as_ref().map(|u| u.to_string()).unwrap_or_default();
+    report(res, &msg, on_event)
+}
+
+async fn update_mcu_inner(
+    ledger_api: &impl AsyncTransport,
+    network: &impl AsyncNetwork,
+    on_event: &mut (dyn FnMut(Event) + Send),
+) -> Result<McuUpdate, BoxError> {
+    let device_info = ready_device_info(ledger_api).await?;
+    check_bootloader_mode(&device_info)?;
+    let (url, body) = next_mcu_request(&device_info);
+    let resp = network
+        .post_json(&url, &body)
+        .await
+        .map_err(|e| format!("Error when querying the next MCU version: {}", e))?;
+    let mcu = match parse_next_mcu(&resp)? {
+        Some(mcu) => mcu,
+        None => return Ok(McuUpdate::UpToDate),
+    };
+
+    let (version, update) = plan_mcu_update(&device_info, mcu);
+    on_event(mcu_update_event(&update, &version));
+    query_via_websocket(
+        ledger_api,
+        network,
+        &mcu_update_url(&device_info, &version),
+        on_event,
+    )
+    .await
+    .map_err(|e| format!("Error when updating the MCU: {}", e))?;
+    Ok(update)
+}
@@ -0,0 +1,516 @@
+//! Record a full session (Manager API responses, HSM websocket messages and APDU exchanges with
+//! the device) to a file, and replay it later without a device nor network access.
+//!
+//! A session file is made of one JSON-serialized [`SessionEvent`] per line. Events are written as
+//! soon as they happen so a session which ends with a crash can still be replayed.
+
+use crate::{
+    network::{HsmSocket, Network},
+    transport::Transport,
+};
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use serde_derive::{Deserialize, Serialize};
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::VecDeque,
+    error, fs,
+    io::{self, BufRead, Write},
+    ops::Deref,
+    path::Path,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+/// A websocket message in a form which can be written to a session file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WsFrame {
+    Text(String),
+    /// Hex-encoded payload.
+    Binary(String),
+    /// Hex-encoded payload.
+    Ping(String),
+    /// Hex-encoded payload.
+    Pong(String),
+    Close(Option<(u16, String)>),
+}
+
+impl From<&tungstenite::Message> for WsFrame {
+    fn from(msg: &tungstenite::Message) -> Self {
+        match msg {
+            tungstenite::Message::Text(t) => Self::Text(t.clone()),
+            tungstenite::Message::Binary(b) => Self::Binary(hex::encode(b)),
+            tungstenite::Message::Ping(b) => Self::Ping(hex::encode(b)),
+            tungstenite::Message::Pong(b) => Self::Pong(hex::encode(b)),
+            tungstenite::Message::Close(c) => Self::Close(
+                c.as_ref()
+                    .map(|c| (u16::from(c.code), c.reason.to_string())),
+            ),
+            // Raw frames are never returned when reading, treat them as binary.
+            tungstenite::Message::Frame(f) => Self::Binary(hex::encode(f.payload())),
+        }
+    }
+}
+
+impl TryFrom<WsFrame> for tungstenite::Message {
+    type Error = hex::FromHexError;
+
+    fn try_from(frame: WsFrame) -> Result<Self, Self::Error> {
+        Ok(match frame {
+            WsFrame::Text(t) => Self::Text(t),
+            WsFrame::Binary(b) => Self::Binary(hex::decode(b)?),
+            WsFrame::Ping(b) => Self::Ping(hex::decode(b)?),
+            WsFrame::Pong(b) => Self::Pong(hex::decode(b)?),
+            WsFrame::Close(c) => {
+                Self::Close(c.map(|(code, reason)| tungstenite::protocol::CloseFrame {
+                    code: code.into(),
+                    reason: Cow::Owned(reason),
+                }))
+            }
+        })
+    }
+}
+
+/// An interaction with the outside world. Outcomes are recorded along with the request so failures
+/// (for instance a dropped connection) are replayed too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// The command which was run, as passed through `LEDGER_COMMAND`.
+    Start { command: String, testnet: bool },
+    /// An HTTP GET request to the Manager API.
+    Get {
+        url: String,
+        response: Result<String, String>,
+    },
+    /// An HTTP POST request to the Manager API.
+    Post {
+        url: String,
+        body: serde_json::Value,
+        response: Result<String, String>,
+    },
+    /// Opening a websocket connection to the HSM.
+    Connect {
+        url: String,
+        result: Result<(), String>,
+    },
+    /// A message read from the websocket.
+    Received { frame: Result<WsFrame, String> },
+    /// A message sent on the websocket.
+    Sent {
+        frame: WsFrame,
+        result: Result<(), String>,
+    },
+    /// An APDU exchange with the device. Both are hex-encoded, the answer includes the status.
+    Apdu {
+        command: String,
+        answer: Result<String, String>,
+    },
+}
+
+/// Appends events to a session file.
+#[derive(Debug)]
+pub struct Recorder {
+    file: RefCell<fs::File>,
+    // The first error when recording an event from the wrappers.
+    error: RefCell<Option<String>>,
+}
+
+impl Recorder {
+    /// Create (or truncate) the session file at this path.
+    pub fn create(path: impl AsRef<Path>) -> Result<Rc<Self>, Box<dyn error::Error>> {
+        let file = fs::File::create(path)?;
+        Ok(Rc::new(Self {
+            file: RefCell::new(file),
+            error: RefCell::new(None),
+        }))
+    }
+
+    /// Write this event to the session file.
+    pub fn record(&self, event: &SessionEvent) -> Result<(), Box<dyn error::Error>> {
+        let line = serde_json::to_string(event)?;
+        writeln!(self.file.borrow_mut(), "{}", line)?;
+        Ok(())
+    }
+
+    /// The first error which occurred when the wrappers recorded an event, if any. Such an error
+    /// doesn't interrupt the operation being recorded but leaves the session file incomplete.
+    pub fn error(&self) -> Option<String> {
+        self.error.borrow().clone()
+    }
+
+    // We don't want a failure to write the session file to interrupt the actual operation.
+    fn record_or_keep_error(&self, event: &SessionEvent) {
+        if let Err(e) = self.record(event) {
+            self.error.borrow_mut().get_or_insert(e.to_string());
+        }
+    }
+}
+
+fn result_string<T, U>(
+    res: &Result<T, Box<dyn error::Error>>,
+    f: impl FnOnce(&T) -> U,
+) -> Result<U, String> {
+    res.as_ref().map(f).map_err(|e| e.to_string())
+}
+
+/// A transport which records all exchanges with the device.
+pub struct RecordingTransport<T> {
+    inner: T,
+    recorder: Rc<Recorder>,
+}
+
+impl<T> RecordingTransport<T> {
+    pub fn new(inner: T, recorder: Rc<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        let res = self.inner.exchange(command);
+        self.recorder.record_or_keep_error(&SessionEvent::Apdu {
+            command: hex::encode(command.serialize()),
+            answer: result_string(&res, |a| {
+                hex::encode([a.apdu_data(), &a.retcode().to_be_bytes()].concat())
+            }),
+        });
+        res
+    }
+
+    fn set_hsm_session(&self, active: bool) {
+        self.inner.set_hsm_session(active)
+    }
+}
+
+/// Records all requests to Ledger's servers as well as all the messages on the websockets.
+pub struct RecordingNetwork<N> {
+    inner: N,
+    recorder: Rc<Recorder>,
+}
+
+impl<N> RecordingNetwork<N> {
+    pub fn new(inner: N, recorder: Rc<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<N: Network> Network for RecordingNetwork<N> {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        let res = self.inner.get(url);
+        self.recorder.record_or_keep_error(&SessionEvent::Get {
+            url: url.to_string(),
+            response: result_string(&res, Clone::clone),
+        });
+        res
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        let res = self.inner.post_json(url, body);
+        self.recorder.record_or_keep_error(&SessionEvent::Post {
+            url: url.to_string(),
+            body: body.clone(),
+            response: result_string(&res, Clone::clone),
+        });
+        res
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        let res = self.inner.connect(url);
+        self.recorder.record_or_keep_error(&SessionEvent::Connect {
+            url: url.to_string(),
+            result: result_string(&res, |_| ()),
+        });
+        Ok(Box::new(RecordingSocket {
+            inner: res?,
+            recorder: self.recorder.clone(),
+        }))
+    }
+}
+
+struct RecordingSocket {
+    inner: Box<dyn HsmSocket>,
+    recorder: Rc<Recorder>,
+}
+
+impl HsmSocket for RecordingSocket {
+    fn read(&mut self) -> Result<tungstenite::Message, Box<dyn error::Error>> {
+        let res = self.inner.read();
+        self.recorder.record_or_keep_error(&SessionEvent::Received {
+            frame: result_string(&res, |m| WsFrame::from(m)),
+        });
+        res
+    }
+
+    fn send(&mut self, msg: tungstenite::Message) -> Result<(), Box<dyn error::Error>> {
+        let frame = WsFrame::from(&msg);
+        let res = self.inner.send(msg);
+        self.recorder.record_or_keep_error(&SessionEvent::Sent {
+            frame,
+            result: result_string(&res, |_| ()),
+        });
+        res
+    }
+
+    fn check_interrupted(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.inner.check_interrupted()
+    }
+
+    fn close(&mut self) -> Result<(), Box<dyn error::Error>> {
+        let res = self.inner.close();
+        self.recorder.record_or_keep_error(&SessionEvent::Sent {
+            frame: WsFrame::Close(None),
+            result: result_string(&res, |_| ()),
+        });
+        res
+    }
+}
+
+/// Serves the events from a recorded session, in order. Any interaction which doesn't match the
+/// next recorded event is an error: the replayed flow diverged from the recorded one.
+///
+/// It can be shared across threads, so a session can also be replayed through the async API.
+#[derive(Debug)]
+pub struct Replayer {
+    events: Mutex<VecDeque<SessionEvent>>,
+}
+
+impl Replayer {
+    /// Load a session from the file at this path.
+    pub fn load(path: impl AsRef<Path>) -> Result<Arc<Self>, Box<dyn error::Error>> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+        let mut events = VecDeque::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(&line)
+                .map_err(|e| format!("Invalid session event at line {}: {}", i + 1, e))?;
+            events.push_back(event);
+        }
+        Ok(Arc::new(Self {
+            events: Mutex::new(events),
+        }))
+    }
+
+    /// Get the command the session was recorded for, if it was recorded.
+    pub fn start(&self) -> Option<(String, bool)> {
+        let mut events = self.events.lock().unwrap();
+        match events.front() {
+            Some(SessionEvent::Start { command, testnet }) => {
+                let start = (command.clone(), *testnet);
+                events.pop_front();
+                Some(start)
+            }
+            _ => None,
+        }
+    }
+
+    /// Number of recorded events which were not replayed.
+    pub fn remaining(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    fn next(&self, expected: &str) -> Result<SessionEvent, Box<dyn error::Error>> {
+        self.events.lock().unwrap().pop_front().ok_or_else(|| {
+            format!(
+                "Session diverged from the recording: expected {} but the recording ended.",
+                expected
+            )
+            .into()
+        })
+    }
+}
+
+fn diverged(expected: &str, event: &SessionEvent) -> Box<dyn error::Error> {
+    format!(
+        "Session diverged from the recording: expected {} but the next recorded event is {:?}.",
+        expected, event
+    )
+    .into()
+}
+
+/// A mock device which answers with the recorded APDU responses.
+pub struct ReplayTransport {
+    replayer: Arc<Replayer>,
+}
+
+impl ReplayTransport {
+    pub fn new(replayer: Arc<Replayer>) -> Self {
+        Self { replayer }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        let command = hex::encode(command.serialize());
+        let expected = format!("APDU command {}", command);
+        match self.replayer.next(&expected)? {
+            SessionEvent::Apdu {
+                command: rec_command,
+                answer,
+            } if rec_command == command => Ok(APDUAnswer::from_answer(hex::decode(answer?)?)?),
+            event => Err(diverged(&expected, &event)),
+        }
+    }
+}
+
+/// Mock servers which answer with the recorded responses.
+pub struct ReplayNetwork {
+    replayer: Arc<Replayer>,
+}
+
+impl ReplayNetwork {
+    pub fn new(replayer: Arc<Replayer>) -> Self {
+        Self { replayer }
+    }
+}
+
+impl Network for ReplayNetwork {
+    fn get(&self, url: &str) -> Result<String, Box<dyn error::Error>> {
+        let expected = format!("GET {}", url);
+        match self.replayer.next(&expected)? {
+            SessionEvent::Get {
+                url: rec_url,
+                response,
+            } if rec_url == url => Ok(response?),
+            event => Err(diverged(&expected, &event)),
+        }
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<String, Box<dyn error::Error>> {
+        let expected = format!("POST {} {}", url, body);
+        match self.replayer.next(&expected)? {
+            SessionEvent::Post {
+                url: rec_url,
+                body: rec_body,
+                response,
+            } if rec_url == url && &rec_body == body => Ok(response?),
+            event => Err(diverged(&expected, &event)),
+        }
+    }
+
+    fn connect(&self, url: &str) -> Result<Box<dyn HsmSocket>, Box<dyn error::Error>> {
+        Ok(Box::new(self.connect_socket(url)?))
+    }
+}
+
+impl ReplayNetwork {
+    fn connect_socket(&self, url: &str) -> Result<ReplaySocket, Box<dyn error::Error>> {
+        let expected = format!("websocket connection to {}", url);
+        match self.replayer.next(&expected)? {
+            SessionEvent::Connect {
+                url: rec_url,
+                result,
+            } if rec_url == url => {
+                result?;
+                Ok(ReplaySocket {
+                    replayer: self.replayer.clone(),
+                })
+            }
+            event => Err(diverged(&expected, &event)),
+        }
+    }
+}
+
+/// A mock websocket connection to the HSM, replaying the recorded messages.
+pub struct ReplaySocket {
+    replayer: Arc<Replayer>,
+}
+
+impl HsmSocket for ReplaySocket {
+    fn read(&mut self) -> Result<tungstenite::Message, Box<dyn error::Error>> {
+        let expected = "a websocket read";
+        match self.replayer.next(expected)? {
+            SessionEvent::Received { frame } => Ok(frame?.try_into()?),
+            event => Err(diverged(expected, &event)),
+        }
+    }
+
+    fn send(&mut self, msg: tungstenite::Message) -> Result<(), Box<dyn error::Error>> {
+        let frame = WsFrame::from(&msg);
+        let expected = format!("sending {:?} on the websocket", frame);
+        match self.replayer.next(&expected)? {
+            SessionEvent::Sent {
+                frame: rec_frame,
+                result,
+            } if rec_frame == frame => Ok(result?),
+            event => Err(diverged(&expected, &event)),
+        }
+    }
+}
+
+// The recording is replayed synchronously, the futures are always ready.
+#[cfg(feature = "async")]
+mod nonblocking {
+    use super::{ReplayNetwork, ReplaySocket};
+    use crate::{
+        network::{HsmSocket, Network},
+        nonblocking::{AsyncHsmSocket, AsyncNetwork, BoxError},
+    };
+
+    use std::future::Future;
+
+    impl AsyncNetwork for ReplayNetwork {
+        type Socket = ReplaySocket;
+
+        fn get(&self, url: &str) -> impl Future<Output = Result<String, BoxError>> + Send {
+            let res = Network::get(self, url).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+
+        fn post_json(
+            &self,
+            url: &str,
+            body: &serde_json::Value,
+        ) -> impl Future<Output = Result<String, BoxError>> + Send {
+            let res = Network::post_json(self, url, body).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+
+        fn connect(
+            &self,
+            url: &str,
+        ) -> impl Future<Output = Result<ReplaySocket, BoxError>> + Send {
+            let res = self.connect_socket(url).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+    }
+
+    impl AsyncHsmSocket for ReplaySocket {
+        fn read(&mut self) -> impl Future<Output = Result<tungstenite::Message, BoxError>> + Send {
+            let res = HsmSocket::read(self).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+
+        fn send(
+            &mut self,
+            msg: tungstenite::Message,
+        ) -> impl Future<Output = Result<(), BoxError>> + Send {
+            let res = HsmSocket::send(self, msg).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+
+        fn close(&mut self) -> impl Future<Output = Result<(), BoxError>> + Send {
+            let res = HsmSocket::close(self).map_err(|e| e.to_string().into());
+            async move { res }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{Event, StatusCode, GET_VERSION_COMMAND};
+
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use ledger_transport_hidapi::{hidapi::HidApi, LedgerHIDError, TransportNativeHID};
+
+use std::{
+    error,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often to check whether the device is ready while waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A means of exchanging APDUs with a Ledger device.
+///
+/// This is implemented for the HID transport to talk to an actual device, but can also be
+/// implemented by wrappers (for instance to record a session) or mocks (to replay one).
+pub trait Transport {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>>;
+
+    /// Called when a session with Ledger's HSM starts and ends. Each command of a session must be
+    /// sent exactly once, so a transport must not send one again on its own while it's active.
+    fn set_hsm_session(&self, _active: bool) {}
+}
+
+impl Transport for TransportNativeHID {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        Ok(TransportNativeHID::exchange(self, command)?)
+    }
+}
+
+// The state of the device while waiting for it to be ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceState {
+    Disconnected,
+    Locked,
+    Ready,
+}
+
+fn device_state(hid_api: &HidApi) -> (DeviceState, Option<TransportNativeHID>) {
+    let ledger_api = match TransportNativeHID::new(hid_api) {
+        Ok(t) => t,
+        Err(_) => return (DeviceState::Disconnected, None),
+    };
+    match ledger_api.exchange(&GET_VERSION_COMMAND) {
+        Ok(answer) if answer.retcode() == StatusCode::LOCKED_DEVICE as u16 => {
+            (DeviceState::Locked, None)
+        }
+        // Could be that an app is open, it's still ready to be talked to.
+        Ok(_) => (DeviceState::Ready, Some(ledger_api)),
+        // It might have been unplugged in the meantime.
+        Err(_) => (DeviceState::Disconnected, None),
+    }
+}
+
+/// Wait until a device is plugged in and unlocked, for at most this long.
+///
+/// An event is emitted whenever the device needs the attention of the user, that is when it's not
+/// plugged in or locked.
+pub fn wait_for_device(
+    timeout: Duration,
+    on_event: &mut dyn FnMut(Event),
+) -> Result<TransportNativeHID, Box<dyn error::Error>> {
+    let start = Instant::now();
+    let mut hid_api = HidApi::new()?;
+    let mut last_state = DeviceState::Ready;
+
+    loop {
+        let (state, ledger_api) = device_state(&hid_api);
+        if let Some(ledger_api) = ledger_api {
+            return Ok(ledger_api);
+        }
+        if state != last_state {
+            on_event(match state {
+                DeviceState::Locked => Event::DeviceLocked,
+                _ => Event::DeviceDisconnected,
+            });
+            last_state = state;
+        }
+
+        if start.elapsed() > timeout {
+            return Err(match state {
+                DeviceState::Locked => "Timed out waiting for the device to be unlocked",
+                _ => "Timed out waiting for a device to be plugged in",
+            }
+            .into());
+        }
+        thread::sleep(POLL_INTERVAL);
+        hid_api.refresh_devices()?;
+    }
+}
+
+/// A connection to a device which is re-opened when lost, for instance because the device
+/// re-enumerated on USB after opening an app or rebooting during a firmware upgrade.
+///
+/// The device is matched by its serial number when re-opening it. A command which failed because
+/// the connection was lost is sent again once reconnected, unless it was part of a session with
+/// Ledger's HSM. Such a session isn't resumed: the operation fails and has to be run again, for
+/// instance to perform the next stage of an MCU update once the device restarted.
+pub struct ReconnectingTransport {
+    hid_api: Mutex<HidApi>,
+    serial: Option<String>,
+    device: Mutex<Option<TransportNativeHID>>,
+    in_hsm_session: AtomicBool,
+    // How long to wait for the device to come back.
+    timeout: Duration,
+}
+
+impl ReconnectingTransport {
+    /// Connect to the first device found. It will be waited for at most `timeout` whenever it
+    /// needs to be re-opened.
+    pub fn new(timeout: Duration) -> Result<Self, Box<dyn error::Error>> {
+        let hid_api = HidApi::new()?;
+        let device_info = TransportNativeHID::list_ledgers(&hid_api)
+            .next()
+            .ok_or("No Ledger device found")?;
+        let serial = device_info.serial_number().map(str::to_string);
+        let device = TransportNativeHID::open_device(&hid_api, device_info)?;
+        Ok(Self {
+            hid_api: Mutex::new(hid_api),
+            serial,
+            device: Mutex::new(Some(device)),
+            in_hsm_session: AtomicBool::new(false),
+            timeout,
+        })
+    }
+
+    // Wait for the device to show up again and open it.
+    fn reopen(&self) -> Result<TransportNativeHID, Box<dyn error::Error>> {
+        let start = Instant::now();
+        let mut hid_api = self.hid_api.lock().map_err(|_| "HID api lock poisoned")?;
+        loop {
+            hid_api.refresh_devices()?;
+            let device_info = TransportNativeHID::list_ledgers(&hid_api)
+                .find(|d| d.serial_number() == self.serial.as_deref());
+            if let Some(device_info) = device_info {
+                if let Ok(device) = TransportNativeHID::open_device(&hid_api, device_info) {
+                    return Ok(device);
+                }
+            }
+
+            if start.elapsed() > self.timeout {
+                return Err("Timed out waiting for the device to reconnect".into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Transport for ReconnectingTransport {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        let mut device = self.device.lock().map_err(|_| "Device lock poisoned")?;
+        let err: Box<dyn error::Error> = match device.as_ref().map(|d| d.exchange(command)) {
+            Some(Ok(answer)) => return Ok(answer),
+            Some(Err(e)) if is_disconnection(&e) => e.into(),
+            Some(Err(e)) => return Err(e.into()),
+            None => "The device is disconnected".into(),
+        };
+
+        // Close the stale handle before opening a new one, as the same device can't be opened
+        // twice.
+        *device = None;
+        let new_device = self
+            .reopen()
+            .map_err(|e| format!("{}. Reconnecting failed: {}", err, e))?;
+        if self.in_hsm_session.load(Ordering::SeqCst) {
+            *device = Some(new_device);
+            return Err(format!("{}. The device reconnected, please try again.", err).into());
+        }
+        let answer = Transport::exchange(&new_device, command);
+        *device = Some(new_device);
+        answer
+    }
+
+    fn set_hsm_session(&self, active: bool) {
+        self.in_hsm_session.store(active, Ordering::SeqCst);
+    }
+}
+
+// Whether the HID transport failed because the device went away, as opposed to an invalid answer.
+fn is_disconnection(e: &LedgerHIDError) -> bool {
+    matches!(e, LedgerHIDError::Hid(_) | LedgerHIDError::DeviceNotFound)
+}
@@ -0,0 +1,78 @@
+//! Tests of the audit of the installed apps against Ledger's catalogue.
+
+use ledger_installer::{audit_apps, AuditStatus, BitcoinAppV2, InstalledApp};
+
+fn installed(name: &str, hash: u8) -> InstalledApp {
+    InstalledApp {
+        name: name.to_string(),
+        hash: vec![hash; 32],
+        hash_code_data: vec![0; 32],
+        blocks: 1,
+        flags: 0,
+    }
+}
+
+fn published(name: &str, version: &str, hash: u8) -> BitcoinAppV2 {
+    serde_json::from_value(serde_json::json!({
+        "versionName": name,
+        "version": version,
+        "perso": "perso_11",
+        "delete": "delete",
+        "deleteKey": "delete_key",
+        "firmware": "firmware",
+        "firmwareKey": "firmware_key",
+        "hash": hex::encode([hash; 32]),
+    }))
+    .unwrap()
+}
+
+fn statuses(apps: &[InstalledApp]) -> Vec<AuditStatus> {
+    let catalogue = [published("Bitcoin", "2.1.3", 3)];
+    let history = [
+        published("Bitcoin", "2.1.3", 3),
+        published("Bitcoin", "2.1.2", 2),
+        published("Ethereum", "1.0.0", 2),
+    ];
+    audit_apps(apps, &catalogue, &history)
+        .into_iter()
+        .map(|a| a.status)
+        .collect()
+}
+
+#[test]
+fn up_to_date() {
+    assert_eq!(
+        statuses(&[installed("bitcoin", 3)]),
+        [AuditStatus::UpToDate]
+    );
+}
+
+#[test]
+fn outdated() {
+    assert_eq!(
+        statuses(&[installed("Bitcoin", 2)]),
+        [AuditStatus::Outdated]
+    );
+}
+
+#[test]
+fn hash_mismatch() {
+    assert_eq!(
+        statuses(&[installed("Bitcoin", 1)]),
+        [AuditStatus::HashMismatch]
+    );
+}
+
+#[test]
+fn hash_of_another_app() {
+    // The hash of a version of another app doesn't make it a published version of this one.
+    let catalogue = [published("Bitcoin", "2.1.3", 3)];
+    let history = [published("Ethereum", "1.0.0", 2)];
+    let audits = audit_apps(&[installed("Bitcoin", 2)], &catalogue, &history);
+    assert_eq!(audits[0].status, AuditStatus::HashMismatch);
+}
+
+#[test]
+fn unknown() {
+    assert_eq!(statuses(&[installed("Foo", 3)]), [AuditStatus::Unknown]);
+}
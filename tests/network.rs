@@ -0,0 +1,92 @@
+//! Tests of the connection to Ledger's servers against local mock servers.
+
+use ledger_installer::network::{LiveNetwork, Network, SessionError};
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn stalled_handshake_times_out() {
+    // Accepts the connection but never answers the websocket handshake.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let network = LiveNetwork {
+        connect_timeout: Duration::from_millis(200),
+        ..LiveNetwork::default()
+    };
+    let err = network.connect(&url).err().unwrap();
+    assert_eq!(err.downcast_ref(), Some(&SessionError::Timeout));
+}
+
+#[test]
+fn error_status_is_an_error() {
+    // Answers the request with an error page.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/apps/by-target", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 5\r\n\r\noops!")
+            .unwrap();
+    });
+
+    let err = LiveNetwork::default().get(&url).unwrap_err();
+    assert!(err.to_string().contains("502 Bad Gateway"), "{}", err);
+    server.join().unwrap();
+}
+
+// Accept a websocket connection and keep it open, without ever reading from it, until told to stop.
+fn unresponsive_hsm() -> (String, mpsc::Sender<()>, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let (stop, stopped) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let _socket = tungstenite::accept(stream).unwrap();
+        let _ = stopped.recv();
+    });
+    (url, stop, server)
+}
+
+#[test]
+fn close_does_not_wait_for_the_hsm() {
+    let (url, stop, server) = unresponsive_hsm();
+    let mut socket = LiveNetwork::default().connect(&url).unwrap();
+
+    // The HSM never acknowledges the close, which must not be waited for the whole read timeout.
+    let start = Instant::now();
+    socket.close().unwrap();
+    let err = socket.read().unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&SessionError::Timeout));
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    stop.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn session_timeout_starts_with_the_session() {
+    let (url, stop, server) = unresponsive_hsm();
+    let network = LiveNetwork {
+        session_timeout: Some(Duration::from_millis(500)),
+        ..LiveNetwork::default()
+    };
+
+    // The time spent before connecting to the HSM doesn't count.
+    thread::sleep(Duration::from_millis(600));
+    let mut socket = network.connect(&url).unwrap();
+    socket.check_interrupted().unwrap();
+    thread::sleep(Duration::from_millis(600));
+    let err = socket.check_interrupted().unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&SessionError::Timeout));
+
+    stop.send(()).unwrap();
+    server.join().unwrap();
+}
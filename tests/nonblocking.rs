@@ -0,0 +1,117 @@
+//! Replay recorded sessions through the async API.
+#![cfg(feature = "async")]
+
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use ledger_installer::{
+    nonblocking::{self, AsyncTransport, BlockingTransport, BoxError},
+    session::{ReplayNetwork, ReplayTransport, Replayer},
+    Event,
+};
+use std::{
+    future::{self, Future},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+fn session_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("sessions")
+        .join(name)
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+fn replay_install_app() {
+    let replayer = Replayer::load(session_path("install_app.session")).unwrap();
+    assert_eq!(replayer.start(), Some(("installapp".to_string(), false)));
+
+    let ledger_api = BlockingTransport::new(ReplayTransport::new(replayer.clone()));
+    let network = ReplayNetwork::new(replayer.clone());
+    let mut events = Vec::new();
+    block_on(nonblocking::install_app(
+        &ledger_api,
+        &network,
+        false,
+        &mut |e| events.push(e),
+    ))
+    .unwrap();
+
+    assert_eq!(replayer.remaining(), 0);
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::Progress(p) if p.sent == p.total && p.total == 2)));
+    assert!(matches!(events.last(), Some(Event::Done(_))));
+}
+
+#[test]
+fn bulk_status_failure() {
+    let replayer = Replayer::load(session_path("bulk_status_failure.session")).unwrap();
+    let ledger_api = BlockingTransport::new(ReplayTransport::new(replayer.clone()));
+    let network = ReplayNetwork::new(replayer.clone());
+    let url = "wss://scriptrunner.api.live.ledger.com/update/install?&targetId=856686596";
+    let err = block_on(nonblocking::query_via_websocket(
+        &ledger_api,
+        &network,
+        url,
+        &mut |_| {},
+    ))
+    .unwrap_err();
+
+    // Same outcome as with the blocking API: the error response was sent to the HSM.
+    assert_eq!(replayer.remaining(), 0);
+    assert!(
+        err.to_string()
+            .starts_with("Command 3 out of 3 in bulk failed with status"),
+        "{}",
+        err
+    );
+}
+
+// A device which never answers, for instance waiting for the user to confirm.
+#[derive(Default)]
+struct StuckTransport {
+    in_session: AtomicBool,
+    exchanged: AtomicBool,
+}
+
+impl AsyncTransport for StuckTransport {
+    fn exchange(
+        &self,
+        _command: APDUCommand<Vec<u8>>,
+    ) -> impl Future<Output = Result<APDUAnswer<Vec<u8>>, BoxError>> + Send {
+        assert!(self.in_session.load(Ordering::SeqCst));
+        self.exchanged.store(true, Ordering::SeqCst);
+        future::pending()
+    }
+
+    fn set_hsm_session(&self, active: bool) {
+        self.in_session.store(active, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn dropped_session_is_signaled() {
+    let replayer = Replayer::load(session_path("exchange_refused.session")).unwrap();
+    let ledger_api = StuckTransport::default();
+    let network = ReplayNetwork::new(replayer.clone());
+    let url = "wss://scriptrunner.api.live.ledger.com/update/install?&targetId=856686596";
+
+    // The future is dropped while waiting for the device.
+    let mut on_event = |_| {};
+    let res = block_on(async {
+        let session = nonblocking::query_via_websocket(&ledger_api, &network, url, &mut on_event);
+        tokio::time::timeout(Duration::from_millis(100), session).await
+    });
+    assert!(res.is_err());
+    assert!(ledger_api.exchanged.load(Ordering::SeqCst));
+    assert!(!ledger_api.in_session.load(Ordering::SeqCst));
+}
@@ -0,0 +1,166 @@
+//! Property tests for the parsers of the device's responses. See also the fuzz targets in `fuzz/`.
+
+use ledger_installer::{DeviceInfo, FirmwareVersion, InstalledApp, RunningApp};
+use proptest::prelude::*;
+
+// Encode a GET_VERSION response for a device which is not in bootloader mode.
+fn version_response(target_id: u32, version: &str, flags: &[u8], mcu_version: &str) -> Vec<u8> {
+    let mut data = target_id.to_be_bytes().to_vec();
+    data.push(version.len() as u8);
+    data.extend(version.as_bytes());
+    data.push(flags.len() as u8);
+    data.extend(flags);
+    data.push(mcu_version.len() as u8 + 1);
+    data.extend(mcu_version.as_bytes());
+    data.push(0);
+    data
+}
+
+// The blocks, flags, hash_code_data, hash and name of an installed app.
+type AppFields = (u16, u16, Vec<u8>, Vec<u8>, String);
+
+// Encode a LIST_APPS response for these apps.
+fn list_apps_response(apps: &[AppFields]) -> Vec<u8> {
+    let mut data = vec![0x01];
+    for (blocks, flags, hash_code_data, hash, name) in apps {
+        data.push(name.len() as u8 + 70);
+        data.extend(blocks.to_be_bytes());
+        data.extend(flags.to_be_bytes());
+        data.extend(hash_code_data);
+        data.extend(hash);
+        data.push(name.len() as u8);
+        data.extend(name.as_bytes());
+    }
+    data
+}
+
+#[test]
+fn firmware_version_order() {
+    let versions = [
+        "1.0.0-alpha",
+        "1.0.0-alpha.1",
+        "1.0.0-alpha.beta",
+        "1.0.0-beta",
+        "1.0.0-beta.2",
+        "1.0.0-beta.11",
+        "1.0.0-rc2",
+        "1.0.0-rc10",
+        "1.0.0",
+        "1.0.1",
+    ];
+    for pair in versions.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        assert!(
+            FirmwareVersion::parse(a).unwrap() < FirmwareVersion::parse(b).unwrap(),
+            "{} < {}",
+            a,
+            b
+        );
+    }
+}
+
+proptest! {
+    #[test]
+    fn device_info_never_panics(data in prop::collection::vec(any::<u8>(), 0..128)) {
+        let _ = DeviceInfo::from_response(&data);
+    }
+
+    #[test]
+    fn device_info_roundtrip(
+        target_id in 0x3000_0000u32..0x4000_0000,
+        version in "[0-9a-z.-]{0,32}",
+        flags in prop::collection::vec(any::<u8>(), 0..8),
+        mcu_version in "[0-9a-z.]{0,16}",
+    ) {
+        let data = version_response(target_id, &version, &flags, &mcu_version);
+        let info = DeviceInfo::from_response(&data).unwrap();
+        prop_assert_eq!(info.target_id, target_id);
+        prop_assert!(!info.is_bootloader);
+        prop_assert_eq!(info.version, version);
+        prop_assert_eq!(info.flags, flags);
+        prop_assert_eq!(info.mcu_version, Some(mcu_version));
+    }
+
+    #[test]
+    fn device_info_osu(major in 0u32..100, minor in 0u32..100, patch in 0u32..100) {
+        let version = format!("{}.{}.{}", major, minor, patch);
+        let data = version_response(0x3310_0004, &format!("{}-osu", version), &[], "");
+        let info = DeviceInfo::from_response(&data).unwrap();
+        prop_assert!(info.is_osu);
+        prop_assert_eq!(&info.version, &version);
+        prop_assert_eq!(info.firmware_version().map(|v| v.to_string()), Some(version));
+    }
+
+    #[test]
+    fn firmware_version_pre_release_order(
+        version in "[0-9]{1,3}\\.[0-9]{1,3}\\.[0-9]{1,3}",
+        a in 0u32..1000,
+        b in 0u32..1000,
+    ) {
+        let parse = |n| FirmwareVersion::parse(&format!("{}-rc{}", version, n)).unwrap();
+        prop_assert_eq!(parse(a).cmp(&parse(b)), a.cmp(&b));
+        prop_assert!(parse(a) < FirmwareVersion::parse(&version).unwrap());
+    }
+
+    #[test]
+    fn firmware_version_roundtrip(
+        major in any::<u32>(),
+        minor in any::<u32>(),
+        patch in any::<u32>(),
+        pre in prop::option::of("[0-9a-z.-]{1,8}"),
+    ) {
+        let version = FirmwareVersion { major, minor, patch, pre };
+        prop_assert_eq!(FirmwareVersion::parse(&version.to_string()), Some(version));
+    }
+
+    #[test]
+    fn installed_apps_never_panic(data in prop::collection::vec(any::<u8>(), 0..256)) {
+        let _ = InstalledApp::from_response(&data);
+    }
+
+    #[test]
+    fn installed_apps_roundtrip(
+        apps in prop::collection::vec(
+            (
+                any::<u16>(),
+                any::<u16>(),
+                prop::collection::vec(any::<u8>(), 32),
+                prop::collection::vec(any::<u8>(), 32),
+                "[a-zA-Z0-9 ]{0,32}",
+            ),
+            0..4,
+        ),
+    ) {
+        let parsed = InstalledApp::from_response(&list_apps_response(&apps)).unwrap();
+        prop_assert_eq!(parsed.len(), apps.len());
+        for (app, (blocks, flags, hash_code_data, hash, name)) in parsed.iter().zip(apps) {
+            prop_assert_eq!(app.blocks, blocks);
+            prop_assert_eq!(app.flags, flags);
+            prop_assert_eq!(&app.hash_code_data, &hash_code_data);
+            prop_assert_eq!(&app.hash, &hash);
+            prop_assert_eq!(&app.name, &name);
+        }
+    }
+
+    #[test]
+    fn running_app_never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+        let _ = RunningApp::from_response(&data);
+    }
+
+    #[test]
+    fn running_app_roundtrip(
+        name in "[a-zA-Z ]{1,16}",
+        version in "[0-9.]{1,8}",
+        flags in prop::collection::vec(any::<u8>(), 0..4),
+    ) {
+        let mut data = vec![0x01];
+        for field in [name.as_bytes(), version.as_bytes(), &flags] {
+            data.push(field.len() as u8);
+            data.extend(field);
+        }
+        let app = RunningApp::from_response(&data).unwrap();
+        prop_assert_eq!(app.name, name);
+        prop_assert_eq!(app.version, version);
+        prop_assert_eq!(app.flags, flags);
+    }
+}
@@ -0,0 +1,318 @@
+//! Regression tests replaying recorded sessions against a mock device and mock servers.
+
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use ledger_installer::{
+    firmware_status, genuine_check, install_app, query_via_websocket,
+    session::{
+        Recorder, RecordingNetwork, RecordingTransport, ReplayNetwork, ReplayTransport, Replayer,
+        SessionEvent,
+    },
+    transport::Transport,
+    update_mcu, Event, FirmwareStatus, McuUpdate,
+};
+use std::{
+    cell::Cell,
+    error, fs,
+    ops::Deref,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn session_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("sessions")
+        .join(name)
+}
+
+fn read_events(path: &PathBuf) -> Vec<SessionEvent> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+const BULK_URL: &str = "wss://scriptrunner.api.live.ledger.com/update/install?&targetId=856686596";
+
+// Replay a session made of a single bulk which fails, returning the error.
+fn replay_failed_bulk(name: &str) -> String {
+    let replayer = Replayer::load(session_path(name)).unwrap();
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let err = query_via_websocket(&ledger_api, &network, BULK_URL, &mut |_| {}).unwrap_err();
+    // The error response was sent to the HSM.
+    assert_eq!(replayer.remaining(), 0);
+    err.to_string()
+}
+
+#[test]
+fn bulk_status_failure() {
+    // The index is the one in the HSM's list, which contains an empty entry.
+    let err = replay_failed_bulk("bulk_status_failure.session");
+    assert!(
+        err.starts_with("Command 3 out of 3 in bulk failed with status"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn bulk_transport_failure() {
+    let err = replay_failed_bulk("bulk_transport_failure.session");
+    assert_eq!(
+        err,
+        "Command 1 out of 1 in bulk failed with error: Device disconnected"
+    );
+}
+
+#[test]
+fn exchange_refused() {
+    let replayer = Replayer::load(session_path("exchange_refused.session")).unwrap();
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let err = query_via_websocket(&ledger_api, &network, BULK_URL, &mut |_| {}).unwrap_err();
+    assert_eq!(replayer.remaining(), 0);
+    assert!(
+        err.to_string()
+            .contains("The device answered a command with status"),
+        "{}",
+        err
+    );
+}
+
+// Replay a session with the HSM alone, returning its outcome.
+fn replay_hsm_session(name: &str) -> Result<Option<serde_json::Value>, String> {
+    let replayer = Replayer::load(session_path(name)).unwrap();
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let res = query_via_websocket(&ledger_api, &network, BULK_URL, &mut |_| {});
+    assert_eq!(replayer.remaining(), 0);
+    res.map_err(|e| e.to_string())
+}
+
+#[test]
+fn ping_is_answered() {
+    // The pong is checked against the recording.
+    assert_eq!(
+        replay_hsm_session("ping_pong.session"),
+        Ok(Some(serde_json::json!("ok")))
+    );
+}
+
+#[test]
+fn close_after_success() {
+    assert_eq!(
+        replay_hsm_session("close_after_success.session"),
+        Ok(Some(serde_json::json!("ok")))
+    );
+}
+
+#[test]
+fn close_with_reason() {
+    assert_eq!(
+        replay_hsm_session("close_with_reason.session"),
+        Err("The HSM closed the connection: Internal error (code 1011).".to_string())
+    );
+}
+
+#[test]
+fn binary_text_message() {
+    assert_eq!(
+        replay_hsm_session("binary_text.session"),
+        Ok(Some(serde_json::json!("ok")))
+    );
+}
+
+// Checks every command is sent within an HSM session.
+struct SessionCheckingTransport {
+    inner: ReplayTransport,
+    in_session: Cell<bool>,
+}
+
+impl Transport for SessionCheckingTransport {
+    fn exchange<I: Deref<Target = [u8]>>(
+        &self,
+        command: &APDUCommand<I>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        assert!(self.in_session.get());
+        self.inner.exchange(command)
+    }
+
+    fn set_hsm_session(&self, active: bool) {
+        self.in_session.set(active);
+    }
+}
+
+#[test]
+fn hsm_session_is_signaled() {
+    let replayer = Replayer::load(session_path("bulk_status_failure.session")).unwrap();
+    let ledger_api = SessionCheckingTransport {
+        inner: ReplayTransport::new(replayer.clone()),
+        in_session: Cell::new(false),
+    };
+    let network = ReplayNetwork::new(replayer.clone());
+    query_via_websocket(&ledger_api, &network, BULK_URL, &mut |_| {}).unwrap_err();
+    // The session is over even though it failed.
+    assert!(!ledger_api.in_session.get());
+    assert_eq!(replayer.remaining(), 0);
+}
+
+#[test]
+fn replay_install_app() {
+    let replayer = Replayer::load(session_path("install_app.session")).unwrap();
+    assert_eq!(replayer.start(), Some(("installapp".to_string(), false)));
+
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let mut events = Vec::new();
+    install_app(&ledger_api, &network, false, &mut |e| events.push(e)).unwrap();
+
+    assert_eq!(replayer.remaining(), 0);
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::Progress(p) if p.sent == p.total && p.total == 2)));
+    assert!(matches!(events.last(), Some(Event::Done(_))));
+}
+
+#[test]
+fn replay_genuine_check() {
+    let replayer = Replayer::load(session_path("genuine_check.session")).unwrap();
+    assert_eq!(replayer.start(), Some(("genuinecheck".to_string(), false)));
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    let before = now();
+    let report = genuine_check(&ledger_api, &network, &mut |_| {}).unwrap();
+    assert_eq!(replayer.remaining(), 0);
+    assert!((before..=now()).contains(&report.timestamp));
+
+    assert_eq!(
+        serde_json::to_value(&report).unwrap(),
+        serde_json::json!({
+            "target_id": 0x33100004,
+            "firmware_version": "1.1.1",
+            "mcu_version": "4.03",
+            "perso": "perso_11",
+            "result": "0000",
+            "timestamp": report.timestamp,
+        })
+    );
+}
+
+#[test]
+fn install_app_not_onboarded() {
+    // Refused right after querying the device, without prompting to list the apps.
+    let replayer = Replayer::load(session_path("install_not_onboarded.session")).unwrap();
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let err = install_app(&ledger_api, &network, false, &mut |_| {}).unwrap_err();
+    assert!(err.to_string().contains("isn't set up yet"), "{}", err);
+    assert_eq!(replayer.remaining(), 0);
+}
+
+fn replay_update_mcu(name: &str) -> McuUpdate {
+    let replayer = Replayer::load(session_path(name)).unwrap();
+    assert_eq!(replayer.start(), Some(("updatemcu".to_string(), false)));
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let update = update_mcu(&ledger_api, &network, &mut |_| {}).unwrap();
+    assert_eq!(replayer.remaining(), 0);
+    update
+}
+
+#[test]
+fn mcu_up_to_date() {
+    // No next MCU version for the bootloader of the device.
+    assert_eq!(
+        replay_update_mcu("mcu_up_to_date.session"),
+        McuUpdate::UpToDate
+    );
+}
+
+#[test]
+fn mcu_bootloader_update() {
+    // The next MCU version can't be installed from the bootloader of the device.
+    assert_eq!(
+        replay_update_mcu("mcu_bootloader_update.session"),
+        McuUpdate::Bootloader("1.18".to_string())
+    );
+}
+
+#[test]
+fn mcu_update() {
+    assert_eq!(
+        replay_update_mcu("mcu_update.session"),
+        McuUpdate::Mcu("2.30".to_string())
+    );
+}
+
+fn replay_firmware_status(name: &str) -> FirmwareStatus {
+    let replayer = Replayer::load(session_path(name)).unwrap();
+    assert_eq!(
+        replayer.start(),
+        Some(("firmware-status".to_string(), false))
+    );
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    let status = firmware_status(&ledger_api, &network).unwrap();
+    assert_eq!(replayer.remaining(), 0);
+    status
+}
+
+#[test]
+fn firmware_up_to_date() {
+    let status = replay_firmware_status("firmware_up_to_date.session");
+    assert_eq!(status.se_version, "1.1.1");
+    assert_eq!(status.mcu_version.as_deref(), Some("4.03"));
+    assert!(status.latest.is_none());
+}
+
+#[test]
+fn firmware_needs_mcu_update() {
+    // The latest firmware only runs with a newer MCU than the device's.
+    let status = replay_firmware_status("firmware_needs_mcu_update.session");
+    let latest = status.latest.unwrap();
+    assert_eq!(latest.version, "1.2.0");
+    assert_eq!(latest.release_notes.as_deref(), Some("Security fixes."));
+    assert_eq!(latest.mcu_versions, ["4.04"]);
+    assert!(latest.needs_mcu_update);
+}
+
+#[test]
+fn record_replay_roundtrip() {
+    let fixture = session_path("install_app.session");
+    let recorded = std::env::temp_dir().join(format!(
+        "ledger_installer_roundtrip_{}.session",
+        std::process::id()
+    ));
+
+    // Record a session by replaying the fixture, which must give back the same events.
+    let replayer = Replayer::load(&fixture).unwrap();
+    let (command, testnet) = replayer.start().unwrap();
+    let recorder = Recorder::create(&recorded).unwrap();
+    recorder
+        .record(&SessionEvent::Start { command, testnet })
+        .unwrap();
+    let ledger_api =
+        RecordingTransport::new(ReplayTransport::new(replayer.clone()), recorder.clone());
+    let network = RecordingNetwork::new(ReplayNetwork::new(replayer.clone()), recorder);
+    install_app(&ledger_api, &network, false, &mut |_| {}).unwrap();
+    assert_eq!(read_events(&recorded), read_events(&fixture));
+
+    // The recording can itself be replayed.
+    let replayer = Replayer::load(&recorded).unwrap();
+    replayer.start().unwrap();
+    let ledger_api = ReplayTransport::new(replayer.clone());
+    let network = ReplayNetwork::new(replayer.clone());
+    install_app(&ledger_api, &network, false, &mut |_| {}).unwrap();
+    assert_eq!(replayer.remaining(), 0);
+
+    fs::remove_file(&recorded).unwrap();
+}